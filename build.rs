@@ -24,8 +24,7 @@ fn main() {
             .args(["rev-parse", "HEAD"])
             .output()
             .unwrap();
-        let git_hash = String::from_utf8(output.stdout).unwrap();
-        git_hash
+        String::from_utf8(output.stdout).unwrap()
     };
     println!("cargo:rustc-env=GIT_HASH={}", git_hash);
 }
@@ -0,0 +1,194 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use hyper::{Body, Request};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::global_application_config;
+use crate::util::MillisecondTimestamp;
+
+/// Longest `x-request-id` accepted from the reverse proxy. Longer ids get replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+/// How many entries may wait for the writer before new ones get dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Where the access log is written to, as configured by `URSA_ACCESS_LOG`.
+#[derive(Debug)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+static QUEUE: OnceLock<mpsc::Sender<String>> = OnceLock::new();
+/// Entries dropped because the writer fell behind, since this was last reported.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Open the configured access log and start writing queued entries to it on a separate thread, so
+/// that slow writes don't hold up request handling. Without one, requests are not logged.
+pub fn init() -> anyhow::Result<()> {
+    let writer: Box<dyn Write + Send> = match &global_application_config.access_log {
+        None => return Ok(()),
+        Some(AccessLogTarget::Stdout) => Box::new(LineWriter::new(std::io::stdout())),
+        Some(AccessLogTarget::File(path)) => Box::new(LineWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+    };
+    let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+    std::thread::Builder::new()
+        .name("access-log".to_owned())
+        .spawn(move || write_entries(writer, receiver))?;
+    let _ = QUEUE.set(sender);
+    Ok(())
+}
+
+fn write_entries(mut writer: Box<dyn Write + Send>, mut receiver: mpsc::Receiver<String>) {
+    while let Some(line) = receiver.blocking_recv() {
+        if let Err(err) = writeln!(writer, "{line}") {
+            warn!(%err, "Could not write access log entry");
+        }
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                dropped,
+                "Dropped access log entries, the access log is falling behind"
+            );
+        }
+    }
+}
+
+/// The id of a request, taken from the `x-request-id` set by a reverse proxy if there is a
+/// usable one, and generated otherwise.
+pub fn request_id(request: &Request<Body>) -> String {
+    request
+        .headers()
+        .get("x-request-id")
+        .and_then(|it| it.to_str().ok())
+        .filter(|it| {
+            (1..=MAX_REQUEST_ID_LENGTH).contains(&it.len())
+                && it.bytes().all(|it| it.is_ascii_graphic())
+        })
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned)
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheResult {
+    Hit,
+    Miss,
+}
+
+#[derive(Default, Debug)]
+struct AccessDetails {
+    rule: Option<String>,
+    principal: Option<Uuid>,
+    upstream_status: Option<u16>,
+    cache: Option<CacheResult>,
+}
+
+/// Details about a request that are only known to the handlers, collected for its access log
+/// entry.
+#[derive(Clone, Default, Debug)]
+pub struct AccessRecord(Arc<Mutex<AccessDetails>>);
+
+impl AccessRecord {
+    fn update(&self, action: impl FnOnce(&mut AccessDetails)) {
+        action(&mut self.0.lock().unwrap_or_else(|it| it.into_inner()));
+    }
+
+    pub fn set_rule(&self, http_path: &str) {
+        self.update(|it| it.rule = Some(http_path.to_owned()));
+    }
+
+    pub fn set_principal(&self, id: Uuid) {
+        self.update(|it| it.principal = Some(id));
+    }
+
+    pub fn set_upstream_status(&self, status: u16) {
+        self.update(|it| it.upstream_status = Some(status));
+    }
+
+    /// Record a cache lookup. A request that needed multiple lookups counts as a miss if any of
+    /// them missed.
+    pub fn add_cache_result(&self, result: CacheResult) {
+        self.update(|it| {
+            if it.cache != Some(CacheResult::Miss) {
+                it.cache = Some(result);
+            }
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct AccessLogEntry<'a> {
+    time: MillisecondTimestamp,
+    request_id: &'a str,
+    method: &'a str,
+    path: &'a str,
+    rule: Option<String>,
+    principal: Option<Uuid>,
+    status: u16,
+    upstream_status: Option<u16>,
+    cache: Option<CacheResult>,
+    duration_ms: f64,
+}
+
+/// Information about a request that is known before it is handled.
+pub struct RequestSummary {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+}
+
+/// Queue the access log entry of a finished request, if there is an access log. The entry is
+/// dropped if the queue is full.
+pub fn log(summary: &RequestSummary, record: AccessRecord, status: u16, duration: Duration) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    let details = std::mem::take(&mut *record.0.lock().unwrap_or_else(|it| it.into_inner()));
+    let entry = AccessLogEntry {
+        time: MillisecondTimestamp::from(SystemTime::now()),
+        request_id: &summary.request_id,
+        method: &summary.method,
+        path: &summary.path,
+        rule: details.rule,
+        principal: details.principal,
+        status,
+        upstream_status: details.upstream_status,
+        cache: details.cache,
+        duration_ms: duration.as_secs_f64() * 1000.0,
+    };
+    let line = match serde_json::to_string(&entry) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!(%err, "Could not serialize access log entry");
+            return;
+        }
+    };
+    if queue.try_send(line).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
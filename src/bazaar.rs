@@ -0,0 +1,208 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response};
+use influxdb::InfluxDbWriteable;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::util::{MillisecondTimestamp, Shutdown};
+use crate::{global_application_config, make_error};
+
+/// How often the bazaar endpoint gets polled. Hypixel refreshes it more often than this, but
+/// there is little value in storing every single update.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QuickStatus {
+    sell_price: f64,
+    sell_volume: u64,
+    sell_moving_week: u64,
+    sell_orders: u64,
+    buy_price: f64,
+    buy_volume: u64,
+    buy_moving_week: u64,
+    buy_orders: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct Product {
+    quick_status: QuickStatus,
+}
+
+#[derive(Deserialize, Debug)]
+struct BazaarResponse {
+    #[serde(rename = "lastUpdated")]
+    last_updated: MillisecondTimestamp,
+    products: BTreeMap<String, Product>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BazaarSnapshot {
+    last_updated: MillisecondTimestamp,
+    products: BTreeMap<String, QuickStatus>,
+}
+
+/// The most recently collected bazaar snapshot, served by [respond_to].
+static LATEST: RwLock<Option<Arc<BazaarSnapshot>>> = RwLock::new(None);
+
+fn latest() -> Option<Arc<BazaarSnapshot>> {
+    LATEST.read().unwrap().clone()
+}
+
+#[derive(InfluxDbWriteable)]
+struct BazaarPoint {
+    time: MillisecondTimestamp,
+    sell_price: f64,
+    sell_volume: u64,
+    sell_moving_week: u64,
+    sell_orders: u64,
+    buy_price: f64,
+    buy_volume: u64,
+    buy_moving_week: u64,
+    buy_orders: u64,
+    #[influxdb(tag)]
+    id: String,
+}
+
+#[tracing::instrument]
+async fn request_bazaar() -> anyhow::Result<BazaarResponse> {
+    let request = Request::builder()
+        .uri("https://api.hypixel.net/v2/skyblock/bazaar")
+        .method(Method::GET)
+        .body(Body::empty())?;
+    let response = global_application_config.client.request(request).await?;
+    if !response.status().is_success() {
+        anyhow::bail!("Bazaar request failed with status {}", response.status());
+    }
+    let buffer = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&buffer)?)
+}
+
+async fn store_snapshot(snapshot: &BazaarSnapshot) -> anyhow::Result<()> {
+    let influx = influxdb::Client::new(&global_application_config.influx_url, "prices");
+    let readings: Vec<_> = snapshot
+        .products
+        .iter()
+        .map(|(id, status)| {
+            BazaarPoint {
+                time: snapshot.last_updated,
+                sell_price: status.sell_price,
+                sell_volume: status.sell_volume,
+                sell_moving_week: status.sell_moving_week,
+                sell_orders: status.sell_orders,
+                buy_price: status.buy_price,
+                buy_volume: status.buy_volume,
+                buy_moving_week: status.buy_moving_week,
+                buy_orders: status.buy_orders,
+                id: id.clone(),
+            }
+            .into_query("bazaar")
+        })
+        .collect();
+    let res = influx.query(readings).await?;
+    info!("Bazaar prices updated in influx: {res}");
+    Ok(())
+}
+
+/// Returns the `lastUpdated` of the collected snapshot
+async fn collect_fallible(
+    last_updated: Option<MillisecondTimestamp>,
+) -> anyhow::Result<MillisecondTimestamp> {
+    let response = request_bazaar().await?;
+    if Some(response.last_updated) == last_updated {
+        debug!("Bazaar did not update since the last poll");
+        return Ok(response.last_updated);
+    }
+    let snapshot = BazaarSnapshot {
+        last_updated: response.last_updated,
+        products: response
+            .products
+            .into_iter()
+            .map(|(id, product)| (id, product.quick_status))
+            .collect(),
+    };
+    let snapshot = Arc::new(snapshot);
+    *LATEST.write().unwrap() = Some(snapshot.clone());
+    // Serving the latest prices does not depend on influx, so an outage only loses history
+    if let Err(er) = store_snapshot(&snapshot).await {
+        warn!(%er, "Could not write bazaar prices to influx");
+    }
+    Ok(response.last_updated)
+}
+
+#[tracing::instrument(skip_all)]
+async fn loop_body(shutdown: Shutdown) {
+    info!("Bazaar collection loop started.");
+    let mut last_updated = None;
+    loop {
+        tokio::select! {
+            _ = shutdown.deadline.cancelled() => {
+                info!("Exiting bazaar loop");
+                return
+            }
+            it = collect_fallible(last_updated) => {
+                match it {
+                    Ok(timestamp) => last_updated = Some(timestamp),
+                    Err(er) => error!(%er, "Encountered error during bazaar collection"),
+                }
+            }
+        }
+        tokio::select! {
+            _ = shutdown.requested.cancelled() => {
+                info!("Exiting bazaar loop");
+                return
+            }
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+pub(crate) fn start_loop(shutdown: &Shutdown) -> JoinHandle<()> {
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        loop_body(shutdown).await;
+    })
+}
+
+pub async fn respond_to(path: &str) -> anyhow::Result<Option<Response<Body>>> {
+    let Some(snapshot) = latest() else {
+        return make_error(503, "Bazaar data has not been collected yet").map(Some);
+    };
+    let body = if path == "products" {
+        serde_json::to_string(&*snapshot)?
+    } else if let Some(product) = path.strip_prefix("products/") {
+        let Some(status) = snapshot.products.get(product) else {
+            return make_error(404, format!("Unknown bazaar product {product}").as_str()).map(Some);
+        };
+        serde_json::to_string(status)?
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(
+        Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .header("Cache-Control", "public, s-maxage=60, max-age=60")
+            .body(body.into())?,
+    ))
+}
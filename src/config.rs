@@ -0,0 +1,427 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use hmac::digest::KeyInit;
+use hmac::Hmac;
+use hyper::{Body, Client};
+use hyper_tls::HttpsConnector;
+use serde_json::{Map, Value};
+
+use crate::access_log::AccessLogTarget;
+use crate::hypixel::{Rule, RuleTrie};
+use crate::storage::StorageBackend;
+use crate::telemetry::TracingLayer;
+use crate::util::Obscure;
+use crate::GlobalApplicationContext;
+
+/// The config file passed via `--config`, read when the configuration is first accessed.
+pub(crate) static CONFIG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Every setting that may appear in the config file, including those only used by some features
+/// or storage backends, so that the same file works for every build.
+const SETTINGS: &[&str] = &[
+    "HYPIXEL_TOKEN",
+    "ANONYMOUS",
+    "RULES",
+    "ADDRESS",
+    "PORT",
+    "TOKEN_LIFESPAN",
+    "SECRET",
+    "STORAGE",
+    "REDIS_URL",
+    "INFLUX_URL",
+    "STATS_EXPORT_INTERVAL",
+    "RATE_LIMIT_TIMEOUT",
+    "RATE_LIMIT_BUCKET",
+    "SHUTDOWN_GRACE_PERIOD",
+    "SHUTDOWN_TIMEOUT",
+    "AH_RECORD_DIR",
+    "ACCESS_LOG",
+    "TRACING",
+    "OTLP_ENDPOINT",
+];
+
+/// Settings from an optional TOML or JSON config file. Every setting `URSA_<NAME>` can also be
+/// given in the file as `<name>` (lowercase, with dashes instead of underscores). Environment
+/// variables take precedence over the file.
+pub(crate) struct ConfigSource {
+    file: Map<String, Value>,
+}
+
+impl ConfigSource {
+    pub fn load(path: Option<&Path>) -> anyhow::Result<ConfigSource> {
+        let file = match path {
+            None => Map::new(),
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Could not read config file {}", path.display()))?;
+                let value: Value = if path.extension().is_some_and(|it| it == "json") {
+                    serde_json::from_str(&text)?
+                } else {
+                    toml::from_str(&text)?
+                };
+                let Value::Object(file) = value else {
+                    anyhow::bail!("Config file {} is not a table", path.display());
+                };
+                file
+            }
+        };
+        Ok(ConfigSource { file })
+    }
+
+    fn file_key(name: &str) -> String {
+        name.to_ascii_lowercase().replace('_', "-")
+    }
+
+    fn file_value(&self, name: &str) -> Option<&Value> {
+        self.file.get(&Self::file_key(name))
+    }
+
+    /// Look up an optional setting in the environment, falling back to the config file. Fails if
+    /// the config file contains something other than a single value for it.
+    pub fn var_opt(&self, name: &str) -> anyhow::Result<Option<String>> {
+        if let Ok(value) = env::var(format!("URSA_{}", name)) {
+            return Ok(Some(value));
+        }
+        match self.file_value(name) {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(Value::Number(value)) => Ok(Some(value.to_string())),
+            Some(Value::Bool(value)) => Ok(Some(value.to_string())),
+            Some(_) => anyhow::bail!(
+                "Expected {} in the config file to be a single value",
+                Self::file_key(name)
+            ),
+            None => Ok(None),
+        }
+    }
+
+    /// Look up a required setting in the environment, falling back to the config file.
+    pub fn var(&self, name: &str) -> anyhow::Result<String> {
+        self.var_opt(name)?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Could not find {} expected to be found in the environment at URSA_{} or as {} in the config file",
+                name,
+                name,
+                Self::file_key(name)
+            )
+        })
+    }
+
+    /// Look up and parse a setting, using the default if it is absent.
+    pub fn parse_or<T>(&self, name: &str, default: T) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match self.var_opt(name)? {
+            Some(value) => self.parse_value(name, &value),
+            None => Ok(default),
+        }
+    }
+
+    /// Look up and parse a required setting.
+    pub fn parse<T>(&self, name: &str) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.parse_value(name, &self.var(name)?)
+    }
+
+    fn parse_value<T>(&self, name: &str, value: &str) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        value
+            .parse::<T>()
+            .with_context(|| format!("Could not parse {name} ({value:?})"))
+    }
+
+    /// Load rules from the files listed in `URSA_RULES`, or from the `rules` array in the config
+    /// file, which may contain both file names and inline rules. File names may also be
+    /// directories or glob patterns. Returns each rule along with where it was loaded from.
+    fn rules(&self, errors: &mut ConfigErrors) -> Vec<(String, Rule)> {
+        let file_rules = self.file_value("RULES");
+        if let Ok(paths) = env::var("URSA_RULES") {
+            return load_rule_files(paths.split(':'), errors);
+        }
+        let sources: Vec<Result<&str, &Value>> = match file_rules {
+            Some(Value::String(paths)) => paths.split(':').map(Ok).collect(),
+            Some(Value::Array(entries)) => entries
+                .iter()
+                .map(|entry| match entry {
+                    Value::String(path) => Ok(path.as_str()),
+                    inline => Err(inline),
+                })
+                .collect(),
+            Some(_) => {
+                errors.push(anyhow::anyhow!(
+                    "Expected rules in the config file to be a list of files and rules"
+                ));
+                vec![]
+            }
+            None => {
+                errors.push(anyhow::anyhow!(
+                    "Could not find RULES expected to be found in the environment at URSA_RULES or as rules in the config file"
+                ));
+                vec![]
+            }
+        };
+        let mut rules = Vec::new();
+        for (index, source) in sources.into_iter().enumerate() {
+            match source {
+                Ok(path) => rules.extend(load_rule_files([path], errors)),
+                Err(value) => {
+                    let name = format!("inline rule #{}", index + 1);
+                    let rule = serde_json::from_value::<Rule>(value.clone())
+                        .with_context(|| format!("Could not load {name}"));
+                    rules.extend(errors.check(rule).map(|rule| (name, rule)));
+                }
+            }
+        }
+        rules
+    }
+
+    fn unknown_keys(&self) -> Vec<String> {
+        self.file
+            .keys()
+            .filter(|key| !SETTINGS.iter().any(|it| Self::file_key(it) == **key))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Expand a rule path into the rule files it refers to. Directories contain all their `*.json`
+/// files, patterns like `rules/v2-*.json` are expanded using glob syntax.
+fn expand_rule_path(path: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = if Path::new(path).is_dir() {
+        std::fs::read_dir(path)
+            .with_context(|| format!("Could not read rule directory {path}"))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|it| it.is_file() && it.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>()
+    } else if path.contains(['*', '?', '[']) {
+        glob::glob(path)
+            .with_context(|| format!("Invalid rule pattern {path}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Could not expand rule pattern {path}"))?
+            .into_iter()
+            .filter(|it| it.is_file())
+            .collect()
+    } else {
+        return Ok(vec![PathBuf::from(path)]);
+    };
+    if files.is_empty() {
+        anyhow::bail!("{path} does not contain any rule files");
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn load_rule_files<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    errors: &mut ConfigErrors,
+) -> Vec<(String, Rule)> {
+    let mut rules = Vec::new();
+    for path in paths {
+        let Some(files) = errors.check(expand_rule_path(path)) else {
+            continue;
+        };
+        for file in files {
+            let rule = std::fs::read(&file)
+                .map_err(anyhow::Error::from)
+                .and_then(|it| Ok(serde_json::from_slice::<Rule>(&it)?))
+                .with_context(|| format!("Could not load rule file {}", file.display()));
+            rules.extend(
+                errors
+                    .check(rule)
+                    .map(|rule| (file.display().to_string(), rule)),
+            );
+        }
+    }
+    rules
+}
+
+/// Collects configuration problems, so that all of them can be reported at once.
+#[derive(Default)]
+pub(crate) struct ConfigErrors(Vec<anyhow::Error>);
+
+impl ConfigErrors {
+    pub fn push(&mut self, error: anyhow::Error) {
+        self.0.push(error);
+    }
+
+    pub fn check<T>(&mut self, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.push(error);
+                None
+            }
+        }
+    }
+
+    fn into_result(self) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!("Found {} problem(s) in the configuration:", self.0.len());
+        for error in self.0 {
+            message.push_str(&format!("\n - {error:#}"));
+        }
+        anyhow::bail!(message)
+    }
+}
+
+pub(crate) fn load_config(path: Option<&Path>) -> anyhow::Result<GlobalApplicationContext> {
+    let source = ConfigSource::load(path)?;
+    let mut errors = ConfigErrors::default();
+    let hypixel_token = errors.check(source.var("HYPIXEL_TOKEN"));
+    let allow_anonymous = errors.check(source.parse_or("ANONYMOUS", false));
+    let rules = source
+        .rules(&mut errors)
+        .into_iter()
+        .map(|(origin, rule)| {
+            errors.check(
+                rule.validate()
+                    .with_context(|| format!("Invalid rule {} in {origin}", rule.http_path)),
+            );
+            rule
+        })
+        .collect::<Vec<_>>();
+    let rule_trie = errors.check(RuleTrie::build(&rules));
+    let address = errors.check(source.parse_or("ADDRESS", IpAddr::from([172, 0, 0, 1])));
+    let port = errors.check(source.parse::<u16>("PORT"));
+    let token_lifespan = errors.check(source.parse_or("TOKEN_LIFESPAN", 3600u64));
+    let key = errors.check(source.var("SECRET").and_then(|secret| {
+        Hmac::new_from_slice(secret.as_bytes()).context("Could not use SECRET as a key")
+    }));
+    let storage = errors.check(source.var_opt("STORAGE").and_then(
+        |storage| match storage.as_deref() {
+            None | Some("redis") => source.var("REDIS_URL").and_then(|url| {
+                redis::Client::open(url.as_str()).context("Could not parse REDIS_URL")?;
+                Ok(StorageBackend::Redis { url: Obscure(url) })
+            }),
+            Some("memory") => Ok(StorageBackend::Memory),
+            Some(other) => Err(anyhow::anyhow!(
+                "Unknown STORAGE {other:?}, expected redis or memory"
+            )),
+        },
+    ));
+    #[cfg(feature = "influxdb")]
+    let influx_url = errors.check(source.var("INFLUX_URL"));
+    #[cfg(feature = "influxdb")]
+    let stats_export_interval = errors.check(source.parse_or("STATS_EXPORT_INTERVAL", 0u64));
+    let rate_limit_lifespan = errors.check(source.parse::<u64>("RATE_LIMIT_TIMEOUT"));
+    let rate_limit_bucket = errors.check(source.parse::<u64>("RATE_LIMIT_BUCKET"));
+    let shutdown_grace_period = errors.check(source.parse_or("SHUTDOWN_GRACE_PERIOD", 0u64));
+    let shutdown_timeout = errors.check(source.parse_or("SHUTDOWN_TIMEOUT", 30u64));
+    #[cfg(feature = "lbin")]
+    let ah_record_directory = errors
+        .check(source.var_opt("AH_RECORD_DIR"))
+        .flatten()
+        .map(PathBuf::from);
+    let access_log =
+        errors
+            .check(source.var_opt("ACCESS_LOG"))
+            .flatten()
+            .map(|target| match target.as_str() {
+                "stdout" => AccessLogTarget::Stdout,
+                _ => AccessLogTarget::File(PathBuf::from(target)),
+            });
+    let tracing_layers = errors.check(source.var_opt("TRACING").and_then(|it| match it {
+        Some(it) => TracingLayer::parse_list(&it).context("Could not parse TRACING"),
+        None => Ok(vec![TracingLayer::Plain]),
+    }));
+    #[cfg(feature = "otlp")]
+    let otlp_endpoint = errors
+        .check(source.var_opt("OTLP_ENDPOINT"))
+        .flatten()
+        .unwrap_or_else(|| crate::telemetry::DEFAULT_OTLP_ENDPOINT.to_owned());
+    for key in source.unknown_keys() {
+        errors.push(anyhow::anyhow!("Unknown setting {key} in the config file"));
+    }
+    errors.into_result()?;
+    let (
+        Some(hypixel_token),
+        Some(allow_anonymous),
+        Some(rule_trie),
+        Some(address),
+        Some(port),
+        Some(token_lifespan),
+        Some(key),
+        Some(storage),
+        Some(rate_limit_lifespan),
+        Some(rate_limit_bucket),
+        Some(shutdown_grace_period),
+        Some(shutdown_timeout),
+    ) = (
+        hypixel_token,
+        allow_anonymous,
+        rule_trie,
+        address,
+        port,
+        token_lifespan,
+        key,
+        storage,
+        rate_limit_lifespan,
+        rate_limit_bucket,
+        shutdown_grace_period,
+        shutdown_timeout,
+    )
+    else {
+        unreachable!("Every missing setting is recorded as an error")
+    };
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, Body>(https);
+    Ok(GlobalApplicationContext {
+        client,
+        address,
+        port,
+        hypixel_token: Obscure(hypixel_token),
+        rules,
+        rule_trie,
+        allow_anonymous,
+        key,
+        storage,
+        default_token_duration: Duration::from_secs(token_lifespan),
+        rate_limit_lifespan: Duration::from_secs(rate_limit_lifespan),
+        rate_limit_bucket,
+        shutdown_grace_period: Duration::from_secs(shutdown_grace_period),
+        shutdown_timeout: Duration::from_secs(shutdown_timeout),
+        access_log,
+        tracing_layers: tracing_layers.unwrap_or_default(),
+        #[cfg(feature = "otlp")]
+        otlp_endpoint,
+        #[cfg(feature = "influxdb")]
+        influx_url: influx_url.unwrap_or_default(),
+        #[cfg(feature = "influxdb")]
+        stats_export_interval: Duration::from_secs(stats_export_interval.unwrap_or_default()),
+        #[cfg(feature = "lbin")]
+        ah_record_directory,
+    })
+}
@@ -14,14 +14,23 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
 use hyper::{Body, Method, Request, Response};
-use redis::Pipeline;
-use serde::Deserialize;
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tracing::{info_span, warn, Instrument};
 use url::Url;
+use uuid::Uuid;
 
-use crate::mojang::JWTPrincipal;
-use crate::util::UrlForRequest;
+use crate::mojang::{self, JWTPrincipal};
+use crate::util::{MillisecondTimestamp, UrlForRequest};
 use crate::{global_application_config, make_error, RequestContext};
+use crate::{metrics, statistics, storage};
 
 #[derive(Deserialize, Debug)]
 pub struct Rule {
@@ -34,98 +43,859 @@ pub struct Rule {
     /// Additional path segments will be transformed into query arguments with names accordion to this array.
     /// If there are extra or missing arguments this endpoint errors
     #[serde(rename = "query-arguments")]
-    pub query_arguments: Vec<String>,
+    pub query_arguments: Vec<QueryArgument>,
+    /// Query parameters that are always sent to hypixel, independent of the request.
+    #[serde(rename = "fixed-parameters", default)]
+    pub fixed_parameters: BTreeMap<String, String>,
+    /// An optional transformation applied to the hypixel response before it is passed on.
+    #[serde(default)]
+    pub transform: Option<ResponseTransform>,
     // TODO: filters
 }
 
+/// A query argument is either just a name, or an object with a name, a type and constraints.
+#[derive(Debug)]
+enum QueryArgumentDefinition {
+    Name(String),
+    Typed(TypedArgumentDefinition),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TypedArgumentDefinition {
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: ArgumentKind,
+    #[serde(default)]
+    optional: bool,
+    default: Option<String>,
+    /// Allowed values of an enum argument
+    values: Option<Vec<String>>,
+    /// Pattern a regex argument needs to match in full
+    pattern: Option<String>,
+    min: Option<i64>,
+    max: Option<i64>,
+}
+
+// Not derived using #[serde(untagged)], which hides why an argument object is invalid
+impl<'de> Deserialize<'de> for QueryArgumentDefinition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::String(name) => Ok(QueryArgumentDefinition::Name(name)),
+            value => serde_json::from_value(value)
+                .map(QueryArgumentDefinition::Typed)
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum ArgumentKind {
+    #[default]
+    String,
+    Uuid,
+    Integer,
+    Enum,
+    Regex,
+}
+
+#[derive(Debug)]
+pub enum ArgumentType {
+    /// Passed on as is
+    String,
+    /// A dashed or undashed uuid, or a player name which gets resolved using the Mojang API.
+    /// Always passed on as an undashed uuid.
+    Uuid,
+    Integer {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    Enum(Vec<String>),
+    Regex(Regex),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "QueryArgumentDefinition")]
+pub struct QueryArgument {
+    pub name: String,
+    pub kind: ArgumentType,
+    /// Optional arguments may be left out at the end of the path
+    pub optional: bool,
+    /// Sent in place of an omitted optional argument
+    pub default: Option<String>,
+}
+
+impl TryFrom<QueryArgumentDefinition> for QueryArgument {
+    type Error = anyhow::Error;
+
+    fn try_from(value: QueryArgumentDefinition) -> Result<Self, Self::Error> {
+        let TypedArgumentDefinition {
+            name,
+            kind,
+            optional,
+            default,
+            values,
+            pattern,
+            min,
+            max,
+        } = match value {
+            QueryArgumentDefinition::Name(name) => {
+                return Ok(QueryArgument {
+                    name,
+                    kind: ArgumentType::String,
+                    optional: false,
+                    default: None,
+                })
+            }
+            QueryArgumentDefinition::Typed(definition) => definition,
+        };
+        let kind = match kind {
+            ArgumentKind::String => ArgumentType::String,
+            ArgumentKind::Uuid => ArgumentType::Uuid,
+            ArgumentKind::Integer => ArgumentType::Integer { min, max },
+            ArgumentKind::Enum => match values {
+                Some(values) if !values.is_empty() => ArgumentType::Enum(values),
+                _ => anyhow::bail!("Enum argument {name} needs a non empty list of values"),
+            },
+            ArgumentKind::Regex => {
+                let Some(pattern) = pattern else {
+                    anyhow::bail!("Regex argument {name} needs a pattern");
+                };
+                ArgumentType::Regex(Regex::new(&format!("^(?:{pattern})$"))?)
+            }
+        };
+        let argument = QueryArgument {
+            optional: optional || default.is_some(),
+            name,
+            kind,
+            default,
+        };
+        if let Some(default) = &argument.default {
+            if let Err(err) = argument.validate(default) {
+                anyhow::bail!("Invalid default value for {}: {}", argument.name, err.error);
+            }
+        }
+        Ok(argument)
+    }
+}
+
+/// A validated argument. Player names still need to be resolved before the value can be sent to
+/// hypixel.
+enum ArgumentValue {
+    Ready(String),
+    PlayerName(String),
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArgumentError {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    argument: Option<String>,
+}
+
+impl ArgumentError {
+    fn new(argument: &QueryArgument, error: String) -> Self {
+        ArgumentError {
+            error,
+            argument: Some(argument.name.clone()),
+        }
+    }
+
+    fn into_response(self, status_code: u16) -> anyhow::Result<Response<Body>> {
+        Ok(Response::builder()
+            .status(status_code)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&self)?.into())?)
+    }
+}
+
+impl QueryArgument {
+    fn validate(&self, value: &str) -> Result<ArgumentValue, ArgumentError> {
+        match &self.kind {
+            ArgumentType::String => Ok(ArgumentValue::Ready(value.to_owned())),
+            ArgumentType::Uuid => {
+                if let Ok(uuid) = Uuid::parse_str(value) {
+                    Ok(ArgumentValue::Ready(uuid.simple().to_string()))
+                } else if mojang::is_valid_player_name(value) {
+                    Ok(ArgumentValue::PlayerName(value.to_owned()))
+                } else {
+                    Err(ArgumentError::new(
+                        self,
+                        "Expected a uuid or player name".to_owned(),
+                    ))
+                }
+            }
+            ArgumentType::Integer { min, max } => {
+                let Ok(number) = value.parse::<i64>() else {
+                    return Err(ArgumentError::new(self, "Expected an integer".to_owned()));
+                };
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    return Err(ArgumentError::new(
+                        self,
+                        format!("Expected an integer between {min:?} and {max:?}"),
+                    ));
+                }
+                Ok(ArgumentValue::Ready(number.to_string()))
+            }
+            ArgumentType::Enum(values) => {
+                if values.iter().any(|it| it == value) {
+                    Ok(ArgumentValue::Ready(value.to_owned()))
+                } else {
+                    Err(ArgumentError::new(
+                        self,
+                        format!("Expected one of {}", values.join(", ")),
+                    ))
+                }
+            }
+            ArgumentType::Regex(regex) => {
+                if regex.is_match(value) {
+                    Ok(ArgumentValue::Ready(value.to_owned()))
+                } else {
+                    Err(ArgumentError::new(
+                        self,
+                        format!("Expected a value matching {}", regex.as_str()),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseTransform {
+    /// Replace base64 encoded, gzipped NBT inventories (`{"type": 0, "data": "..."}`) with their
+    /// JSON representation.
+    DecodeInventories,
+}
+
+impl ResponseTransform {
+    fn apply(self, response: &mut Value) {
+        match self {
+            ResponseTransform::DecodeInventories => decode_inventories(response),
+        }
+    }
+}
+
+/// Recursively decode all inventory blobs in a response. Blobs that fail to decode are left as is.
+fn decode_inventories(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            if map.get("type").and_then(Value::as_i64) == Some(0) {
+                if let Some(Value::String(data)) = map.get("data") {
+                    if let Ok(nbt) = crate::item::decode_nbt(data) {
+                        map.insert("data".to_owned(), crate::nbt::compound_to_json(&nbt));
+                        return;
+                    }
+                }
+            }
+            map.values_mut().for_each(decode_inventories);
+        }
+        Value::Array(values) => values.iter_mut().for_each(decode_inventories),
+        _ => {}
+    }
+}
+
 impl Rule {
-    pub fn accumulated_statistics_key(&self) -> String {
-        format!("hypixel:accumulated:{}", self.http_path)
+    /// Check constraints between arguments that cannot be expressed in the rule format itself.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        let mut seen_optional = false;
+        for argument in &self.query_arguments {
+            if !names.insert(argument.name.as_str()) {
+                anyhow::bail!("Duplicate query argument {}", argument.name);
+            }
+            if seen_optional && !argument.optional {
+                anyhow::bail!(
+                    "Required query argument {} follows an optional argument",
+                    argument.name
+                );
+            }
+            seen_optional |= argument.optional;
+        }
+        if let Some(name) = self
+            .fixed_parameters
+            .keys()
+            .find(|it| names.contains(it.as_str()))
+        {
+            anyhow::bail!("Fixed parameter {name} is also a query argument");
+        }
+        Ok(())
+    }
+
+    fn parse_arguments(
+        &self,
+        parts: &[&str],
+    ) -> Result<Vec<(String, ArgumentValue)>, ArgumentError> {
+        if let Some(extra) = parts.get(self.query_arguments.len()) {
+            return Err(ArgumentError {
+                error: format!("Superfluous query argument {:?}", extra),
+                argument: None,
+            });
+        }
+        let mut arguments = Vec::with_capacity(self.query_arguments.len());
+        for (index, argument) in self.query_arguments.iter().enumerate() {
+            let value = match (parts.get(index), &argument.default) {
+                (Some(part), _) => argument.validate(part)?,
+                (None, Some(default)) => argument.validate(default)?,
+                (None, None) if argument.optional => continue,
+                (None, None) => {
+                    return Err(ArgumentError::new(
+                        argument,
+                        "Missing query argument".to_owned(),
+                    ))
+                }
+            };
+            arguments.push((argument.name.clone(), value));
+        }
+        Ok(arguments)
     }
 }
 
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|it| !it.is_empty())
+}
+
+/// Routes request paths to rules by exact path segments. Built once when loading the rules.
+#[derive(Debug, Default)]
+pub struct RuleTrie {
+    rule: Option<usize>,
+    children: BTreeMap<String, RuleTrie>,
+}
+
+impl RuleTrie {
+    /// Build the routing trie, reporting every duplicate or ambiguous rule instead of stopping at the first.
+    pub fn build(rules: &[Rule]) -> anyhow::Result<RuleTrie> {
+        let mut trie = RuleTrie::default();
+        let mut errors = Vec::new();
+        for (index, rule) in rules.iter().enumerate() {
+            if path_segments(&rule.http_path).next().is_none() {
+                errors.push(format!("Rule {:?} has an empty http-path", rule.http_path));
+                continue;
+            }
+            let mut node = &mut trie;
+            for segment in path_segments(&rule.http_path) {
+                node = node.children.entry(segment.to_owned()).or_default();
+            }
+            if let Some(existing) = node.rule {
+                errors.push(format!(
+                    "Rule {:?} duplicates the http-path of rule {:?}",
+                    rule.http_path, rules[existing].http_path
+                ));
+                continue;
+            }
+            node.rule = Some(index);
+        }
+        trie.find_ambiguities(rules, None, &mut errors);
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid rule routing:\n{}", errors.join("\n"));
+        }
+        Ok(trie)
+    }
+
+    /// A rule below another rule that accepts query arguments could also be read as arguments to the
+    /// outer rule, so such rules are rejected.
+    fn find_ambiguities(&self, rules: &[Rule], parent: Option<usize>, errors: &mut Vec<String>) {
+        let mut parent = parent;
+        if let Some(index) = self.rule {
+            if let Some(parent) = parent {
+                errors.push(format!(
+                    "Rule {:?} is ambiguous with the query arguments of rule {:?}",
+                    rules[index].http_path, rules[parent].http_path
+                ));
+            }
+            if !rules[index].query_arguments.is_empty() {
+                parent = Some(index);
+            }
+        }
+        for child in self.children.values() {
+            child.find_ambiguities(rules, parent, errors);
+        }
+    }
+
+    /// Find the rule for a path, returning its index and the remaining path segments.
+    pub fn lookup<'a>(&self, path: &'a str) -> Option<(usize, Vec<&'a str>)> {
+        let segments = path_segments(path).collect::<Vec<_>>();
+        let mut node = self;
+        let mut found = None;
+        for (depth, segment) in segments.iter().enumerate() {
+            let Some(child) = node.children.get(*segment) else {
+                break;
+            };
+            node = child;
+            if let Some(index) = node.rule {
+                found = Some((index, depth + 1));
+            }
+        }
+        let (index, consumed) = found?;
+        Some((index, segments[consumed..].to_vec()))
+    }
+
+    /// All rule indices in routing order.
+    pub fn rule_indices(&self) -> Vec<usize> {
+        let mut indices = Vec::new();
+        self.collect_indices(&mut indices);
+        indices
+    }
+
+    fn collect_indices(&self, indices: &mut Vec<usize>) {
+        indices.extend(self.rule);
+        for child in self.children.values() {
+            child.collect_indices(indices);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RouteArgument<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    optional: bool,
+    default: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Route<'a> {
+    path: String,
+    hypixel_path: &'a str,
+    arguments: Vec<RouteArgument<'a>>,
+    fixed_parameters: &'a BTreeMap<String, String>,
+    transform: Option<ResponseTransform>,
+}
+
+impl ArgumentType {
+    fn name(&self) -> &'static str {
+        match self {
+            ArgumentType::String => "string",
+            ArgumentType::Uuid => "uuid",
+            ArgumentType::Integer { .. } => "integer",
+            ArgumentType::Enum(_) => "enum",
+            ArgumentType::Regex(_) => "regex",
+        }
+    }
+}
+
+/// The effective routing table, as served by `/_meta/rules`.
+pub fn routing_table() -> anyhow::Result<String> {
+    let rules = &global_application_config.rules;
+    let routes = global_application_config
+        .rule_trie
+        .rule_indices()
+        .into_iter()
+        .map(|index| {
+            let rule = &rules[index];
+            let mut path = String::from("/v1/hypixel");
+            for segment in path_segments(&rule.http_path) {
+                path.push('/');
+                path.push_str(segment);
+            }
+            for argument in &rule.query_arguments {
+                let marker = if argument.optional { "?" } else { "" };
+                path.push_str(&format!("/{{{}{marker}}}", argument.name));
+            }
+            Route {
+                path,
+                hypixel_path: &rule.hypixel_path,
+                arguments: rule
+                    .query_arguments
+                    .iter()
+                    .map(|argument| RouteArgument {
+                        name: &argument.name,
+                        kind: argument.kind.name(),
+                        optional: argument.optional,
+                        default: argument.default.as_deref(),
+                    })
+                    .collect(),
+                fixed_parameters: &rule.fixed_parameters,
+                transform: rule.transform,
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_string(&routes)?)
+}
+
+/// Consecutive failed hypixel requests after which the circuit opens.
+const CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+/// How long the circuit stays open before a request is let through to hypixel again.
+const CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+static CONSECUTIVE_FAILURES: AtomicU32 = AtomicU32::new(0);
+/// Millisecond timestamp until which the circuit is open, or 0 if it is closed.
+static CIRCUIT_OPEN_UNTIL: AtomicU64 = AtomicU64::new(0);
+/// Whether a request is currently probing hypixel while the circuit is half open.
+static CIRCUIT_PROBING: AtomicBool = AtomicBool::new(false);
+
+/// Circuit breaker state for the hypixel upstream. While open, requests are rejected without
+/// contacting hypixel. Once the cooldown ends the circuit is half open, and a single probe request
+/// decides whether it closes or opens again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+pub fn circuit_state() -> CircuitState {
+    let open_until = CIRCUIT_OPEN_UNTIL.load(Ordering::SeqCst);
+    if open_until == 0 {
+        CircuitState::Closed
+    } else if MillisecondTimestamp::from(SystemTime::now()).0 < open_until {
+        CircuitState::Open
+    } else {
+        CircuitState::HalfOpen
+    }
+}
+
+/// Held by the one request probing hypixel while the circuit is half open. Dropping it lets the
+/// next request probe, so an abandoned probe can not keep the circuit half open forever.
+struct CircuitProbe;
+
+impl Drop for CircuitProbe {
+    fn drop(&mut self) {
+        CIRCUIT_PROBING.store(false, Ordering::SeqCst);
+    }
+}
+
+enum Admission {
+    Allowed,
+    Probe(CircuitProbe),
+    Rejected,
+}
+
+/// Decide whether a request may be sent to hypixel. While the circuit is half open, only one
+/// request at a time is let through, and has to keep its [CircuitProbe] until its result is
+/// recorded.
+fn admit_upstream_request() -> Admission {
+    match circuit_state() {
+        CircuitState::Closed => Admission::Allowed,
+        CircuitState::Open => Admission::Rejected,
+        CircuitState::HalfOpen => {
+            match CIRCUIT_PROBING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => Admission::Probe(CircuitProbe),
+                Err(_) => Admission::Rejected,
+            }
+        }
+    }
+}
+
+/// Failures are connection errors, 429s and server errors, which hint at hypixel being
+/// overloaded rather than the request being invalid.
+fn record_upstream_result(success: bool) {
+    if success {
+        CONSECUTIVE_FAILURES.store(0, Ordering::SeqCst);
+        CIRCUIT_OPEN_UNTIL.store(0, Ordering::SeqCst);
+        return;
+    }
+    let failures = CONSECUTIVE_FAILURES.fetch_add(1, Ordering::SeqCst) + 1;
+    if failures >= CIRCUIT_FAILURE_THRESHOLD {
+        let open_until = MillisecondTimestamp::from(SystemTime::now()) + CIRCUIT_COOLDOWN;
+        if CIRCUIT_OPEN_UNTIL.swap(open_until.0, Ordering::SeqCst) == 0 {
+            warn!("Opening the hypixel circuit after {failures} consecutive failures");
+        }
+    }
+}
+
+/// Resolve player names in validated arguments. Returns an error response for unknown players.
+async fn resolve_arguments(
+    context: &mut RequestContext,
+    arguments: Vec<(String, ArgumentValue)>,
+) -> anyhow::Result<Result<Vec<(String, String)>, Response<Body>>> {
+    let mut resolved = Vec::with_capacity(arguments.len());
+    for (name, value) in arguments {
+        let value = match value {
+            ArgumentValue::Ready(value) => value,
+            ArgumentValue::PlayerName(player) => {
+                let lookup = mojang::resolve_player_name(context.storage.as_ref(), &player).await;
+                if let Ok((_, cache)) = &lookup {
+                    context.access.add_cache_result(*cache);
+                }
+                match lookup {
+                    Ok((Some(uuid), _)) => uuid.simple().to_string(),
+                    Ok((None, _)) => {
+                        return Ok(Err(ArgumentError {
+                            error: format!("Unknown player {player}"),
+                            argument: Some(name),
+                        }
+                        .into_response(404)?))
+                    }
+                    Err(err) => {
+                        warn!(%err, "Could not resolve player name");
+                        return Ok(Err(make_error(502, "Failed to resolve player name")?));
+                    }
+                }
+            }
+        };
+        resolved.push((name, value));
+    }
+    Ok(Ok(resolved))
+}
+
 pub async fn respond_to(
     context: &mut RequestContext,
     path: &str,
     principal: JWTPrincipal,
 ) -> anyhow::Result<Option<Response<Body>>> {
-    for rule in &global_application_config.rules {
-        if let Some(prefix) = path.strip_prefix(&rule.http_path) {
-            let parts = prefix
-                .split('/')
-                .filter(|it| !it.is_empty())
-                .collect::<Vec<_>>();
-            let mut query_parts: Vec<(String, String)> =
-                Vec::with_capacity(rule.query_arguments.len());
-            let mut part_iter = parts.iter();
-            for query_argument in &rule.query_arguments {
-                let Some(next_part) = part_iter.next() else {
-                    return make_error(
-                        400,
-                        format!("Missing query argument {}", query_argument).as_str(),
-                    )
-                    .map(Some);
-                };
-                query_parts.push((query_argument.clone(), (*next_part).to_owned()));
-            }
-            if let Some(extra) = part_iter.next() {
-                return make_error(
-                    400,
-                    format!("Superfluous query argument {:?}", extra).as_str(),
-                )
-                .map(Some);
-            }
-            let url = Url::parse_with_params(rule.hypixel_path.as_str(), query_parts)?;
-            let mut diagnostics_key = String::new();
-            for part in parts {
-                if !diagnostics_key.is_empty() {
-                    diagnostics_key.push(':');
-                }
-                diagnostics_key.push_str(part);
-            }
-            let bucket = principal.ratelimit_key();
-            let resp: ((), (), u64, ()) = Pipeline::new()
-                .zincr(
-                    format!("hypixel:request:{}", rule.http_path),
-                    diagnostics_key,
-                    1,
-                )
-                .cmd("EXPIRE")
-                .arg(&bucket)
-                .arg(global_application_config.rate_limit_lifespan.as_secs())
-                .arg("NX")
-                .incr(&bucket, 1)
-                .incr(rule.accumulated_statistics_key(), 1)
-                .query_async(&mut context.redis_client.0)
-                .await?;
-            let bucket_usage = resp.2;
-            if bucket_usage > global_application_config.rate_limit_bucket
-                && !global_application_config.allow_anonymous
-            {
-                return make_error(429, "Rate limit exceeded").map(Some);
-            }
-
-            let hypixel_request = Request::builder()
-                .url(url)?
-                .method(Method::GET)
-                .header("API-Key", &global_application_config.hypixel_token.0)
-                .body(Body::empty())?;
-            let hypixel_response = global_application_config
-                .client
-                .request(hypixel_request)
-                .await?;
-            // TODO: add temporary global backoff when hitting an error (especially 429)
-            if hypixel_response.status().as_u16() != 200 {
-                return make_error(502, "Failed to request hypixel upstream").map(Some);
-            }
-            return Ok(Some(
-                Response::builder()
-                    .header("Age", "0")
-                    .header("Cache-Control", "public, s-maxage=60, max-age=300")
-                    .header("Content-Type", "application/json")
-                    .body(hypixel_response.into_body())?,
-            ));
-        }
-    }
-    Ok(None)
+    let Some((index, parts)) = global_application_config.rule_trie.lookup(path) else {
+        return Ok(None);
+    };
+    let rule = &global_application_config.rules[index];
+    context.access.set_rule(&rule.http_path);
+    let start = Instant::now();
+    let response = respond_to_rule(context, rule, &parts, principal).await;
+    let status = response.as_ref().map_or(500, |it| it.status().as_u16());
+    metrics::record_request(&rule.http_path, status, start.elapsed());
+    response.map(Some)
+}
+
+#[tracing::instrument(skip_all, fields(rule = %rule.http_path))]
+async fn respond_to_rule(
+    context: &mut RequestContext,
+    rule: &Rule,
+    parts: &[&str],
+    principal: JWTPrincipal,
+) -> anyhow::Result<Response<Body>> {
+    let arguments = match rule.parse_arguments(parts) {
+        Ok(it) => it,
+        Err(err) => return err.into_response(400),
+    };
+    if circuit_state() == CircuitState::Open {
+        return make_error(503, "Hypixel is currently unavailable, try again later");
+    }
+    let bucket = principal.ratelimit_key();
+    let bucket_usage = storage::increment_rate_limit(
+        context.storage.as_ref(),
+        &bucket,
+        global_application_config.rate_limit_lifespan,
+    )
+    .await?;
+    if let Err(err) = statistics::record_request(context.storage.as_ref(), &rule.http_path).await {
+        warn!(%err, "Could not record request statistics");
+    }
+    if bucket_usage > global_application_config.rate_limit_bucket
+        && !global_application_config.allow_anonymous
+    {
+        return make_error(429, "Rate limit exceeded");
+    }
+    let query_parts = match resolve_arguments(context, arguments).await? {
+        Ok(it) => it,
+        Err(response) => return Ok(response),
+    };
+    let mut diagnostics_key = String::new();
+    for (_, value) in &query_parts {
+        if !diagnostics_key.is_empty() {
+            diagnostics_key.push(':');
+        }
+        diagnostics_key.push_str(value);
+    }
+    if let Err(err) =
+        statistics::record_arguments(context.storage.as_ref(), &rule.http_path, &diagnostics_key)
+            .await
+    {
+        warn!(%err, "Could not record request diagnostics");
+    }
+    let url = Url::parse_with_params(
+        rule.hypixel_path.as_str(),
+        query_parts
+            .iter()
+            .map(|(name, value)| (name, value))
+            .chain(&rule.fixed_parameters),
+    )?;
+
+    let upstream_span = info_span!("upstream", path = url.path());
+    let hypixel_request = Request::builder()
+        .url(url)?
+        .method(Method::GET)
+        .header("API-Key", &global_application_config.hypixel_token.0)
+        .body(Body::empty())?;
+    let _probe = match admit_upstream_request() {
+        Admission::Allowed => None,
+        Admission::Probe(probe) => Some(probe),
+        Admission::Rejected => {
+            return make_error(503, "Hypixel is currently unavailable, try again later")
+        }
+    };
+    let hypixel_response = match global_application_config
+        .client
+        .request(hypixel_request)
+        .instrument(upstream_span)
+        .await
+    {
+        Ok(it) => it,
+        Err(err) => {
+            record_upstream_result(false);
+            return Err(err.into());
+        }
+    };
+    let status = hypixel_response.status();
+    context.access.set_upstream_status(status.as_u16());
+    record_upstream_result(!(status.is_server_error() || status.as_u16() == 429));
+    if status.as_u16() != 200 {
+        return make_error(502, "Failed to request hypixel upstream");
+    }
+    let body = match rule.transform {
+        None => hypixel_response.into_body(),
+        Some(transform) => {
+            let buffer = hyper::body::to_bytes(hypixel_response.into_body()).await?;
+            tokio::task::spawn_blocking(move || -> anyhow::Result<Body> {
+                let mut response: Value = serde_json::from_slice(&buffer)?;
+                transform.apply(&mut response);
+                Ok(serde_json::to_vec(&response)?.into())
+            })
+            .await??
+        }
+    };
+    Ok(Response::builder()
+        .header("Age", "0")
+        .header("Cache-Control", "public, s-maxage=60, max-age=300")
+        .header("Content-Type", "application/json")
+        .body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(http_path: &str, query_arguments: Value) -> Rule {
+        serde_json::from_value(serde_json::json!({
+            "http-path": http_path,
+            "hypixel-path": "https://api.hypixel.net/test",
+            "query-arguments": query_arguments,
+        }))
+        .unwrap()
+    }
+
+    fn argument(definition: Value) -> anyhow::Result<QueryArgument> {
+        Ok(serde_json::from_value(definition)?)
+    }
+
+    fn ready(result: Result<ArgumentValue, ArgumentError>) -> Option<String> {
+        match result {
+            Ok(ArgumentValue::Ready(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn trie_looks_up_the_longest_matching_rule() {
+        let rules = [
+            rule("skyblock", serde_json::json!([])),
+            rule("skyblock/profiles", serde_json::json!(["uuid"])),
+            rule("/status/", serde_json::json!([])),
+        ];
+        let trie = RuleTrie::build(&rules).unwrap();
+        assert_eq!(trie.lookup("skyblock"), Some((0, vec![])));
+        assert_eq!(trie.lookup("skyblock/profiles/abc"), Some((1, vec!["abc"])));
+        assert_eq!(trie.lookup("status"), Some((2, vec![])));
+        assert_eq!(trie.lookup("skyblock/other"), Some((0, vec!["other"])));
+        assert_eq!(trie.lookup("unknown"), None);
+        assert_eq!(trie.lookup("skyblockprofiles"), None);
+    }
+
+    #[test]
+    fn trie_reports_duplicate_and_empty_paths() {
+        let rules = [
+            rule("status", serde_json::json!([])),
+            rule("/status", serde_json::json!([])),
+            rule("/", serde_json::json!([])),
+        ];
+        let err = RuleTrie::build(&rules).unwrap_err().to_string();
+        assert!(err.contains("\"/status\" duplicates the http-path of rule \"status\""));
+        assert!(err.contains("\"/\" has an empty http-path"));
+    }
+
+    #[test]
+    fn trie_reports_rules_below_rules_with_arguments() {
+        let rules = [
+            rule("player", serde_json::json!(["uuid"])),
+            rule("player/extra", serde_json::json!([])),
+            rule("status", serde_json::json!([])),
+            rule("status/extra", serde_json::json!([])),
+        ];
+        let err = RuleTrie::build(&rules).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid rule routing:\n\
+             Rule \"player/extra\" is ambiguous with the query arguments of rule \"player\""
+        );
+    }
+
+    #[test]
+    fn validates_typed_arguments() {
+        let uuid = argument(serde_json::json!({"name": "uuid", "type": "uuid"})).unwrap();
+        assert_eq!(
+            ready(uuid.validate("069a79f4-44e9-4726-a5be-fca90e38aaf5")).as_deref(),
+            Some("069a79f444e94726a5befca90e38aaf5")
+        );
+        assert!(matches!(
+            uuid.validate("Notch"),
+            Ok(ArgumentValue::PlayerName(name)) if name == "Notch"
+        ));
+        assert!(uuid.validate("not a name").is_err());
+
+        let page =
+            argument(serde_json::json!({"name": "page", "type": "integer", "min": 0, "max": 10}))
+                .unwrap();
+        assert_eq!(ready(page.validate("010")).as_deref(), Some("10"));
+        assert!(page.validate("11").is_err());
+        assert!(page.validate("-1").is_err());
+        assert!(page.validate("one").is_err());
+
+        let mode =
+            argument(serde_json::json!({"name": "mode", "type": "enum", "values": ["a", "b"]}))
+                .unwrap();
+        assert_eq!(ready(mode.validate("b")).as_deref(), Some("b"));
+        assert!(mode.validate("c").is_err());
+
+        let id = argument(serde_json::json!({"name": "id", "type": "regex", "pattern": "[a-z]+"}))
+            .unwrap();
+        assert_eq!(ready(id.validate("abc")).as_deref(), Some("abc"));
+        assert!(id.validate("abc1").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_argument_definitions() {
+        assert!(
+            argument(serde_json::json!({"name": "mode", "type": "enum", "values": []})).is_err()
+        );
+        assert!(argument(serde_json::json!({"name": "id", "type": "regex"})).is_err());
+        assert!(argument(serde_json::json!({"name": "id", "typo": "uuid"})).is_err());
+        assert!(argument(
+            serde_json::json!({"name": "page", "type": "integer", "max": 5, "default": "6"})
+        )
+        .is_err());
+        let page = argument(serde_json::json!({"name": "page", "type": "integer", "default": "1"}))
+            .unwrap();
+        assert!(page.optional);
+    }
+
+    #[test]
+    fn parses_path_arguments() {
+        let guild = rule(
+            "guild",
+            serde_json::json!(["id", {"name": "page", "type": "integer", "default": "0"}]),
+        );
+        guild.validate().unwrap();
+        let arguments = guild.parse_arguments(&["abc"]).unwrap();
+        let values = arguments
+            .into_iter()
+            .map(|(name, value)| (name, ready(Ok(value))))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                ("id".to_owned(), Some("abc".to_owned())),
+                ("page".to_owned(), Some("0".to_owned()))
+            ]
+        );
+        assert!(guild.parse_arguments(&[]).is_err());
+        assert!(guild.parse_arguments(&["abc", "1", "extra"]).is_err());
+        let misordered = rule(
+            "guild",
+            serde_json::json!([{"name": "page", "optional": true}, "id"]),
+        );
+        assert!(misordered.validate().is_err());
+    }
 }
@@ -0,0 +1,112 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use base64::Engine;
+use simdnbt::owned::{BaseNbt, NbtCompound, NbtTag};
+
+macro_rules! nbt_use {
+    ($o:expr, $n:expr, $t:ident) => {
+        match ::simdnbt::owned::NbtCompound::get($o, $n) {
+            ::std::option::Option::Some(::simdnbt::owned::NbtTag::$t(it)) => {
+                ::std::option::Option::Some(it)
+            }
+            _ => return ::std::option::Option::None,
+        }
+    };
+}
+
+/// Decode base64 encoded, gzip compressed NBT, as used by Hypixel and clients to transfer items.
+#[tracing::instrument(skip_all)]
+pub fn decode_nbt(data: &str) -> anyhow::Result<BaseNbt> {
+    let compressed = base64::engine::general_purpose::STANDARD.decode(data.as_bytes())?;
+    crate::nbt::read_nbt(&compressed)
+}
+
+/// Decode a single item as sent by Hypixel and NEU, which wrap the item stack in an `i` list.
+pub fn decode_item_stack(data: &str) -> anyhow::Result<NbtCompound> {
+    let nbt = decode_nbt(data)?;
+    match nbt.as_compound().take("i") {
+        None => anyhow::bail!("Missing root i tag"),
+        Some(NbtTag::List(list)) => list
+            .into_compounds()
+            .ok_or(anyhow::anyhow!("Expected compound tag"))?
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!("Empty root i tag")),
+        _ => anyhow::bail!("Misshapen root tag"),
+    }
+}
+
+pub struct ItemStack<'a>(&'a NbtCompound);
+
+impl<'a> ItemStack<'a> {
+    pub fn new(compound: &'a NbtCompound) -> Self {
+        Self(compound)
+    }
+    /// Whether this looks like an actual item stack, with an item id and a tag compound.
+    pub fn is_item(&self) -> bool {
+        self.0.get("id").is_some() && matches!(self.0.get("tag"), Some(NbtTag::Compound(_)))
+    }
+    pub fn extra_attributes(&self) -> Option<ExtraAttributes<'a>> {
+        let tag = nbt_use!(self.0, "tag", Compound)?;
+        let extra_attr = nbt_use!(tag, "ExtraAttributes", Compound)?;
+        Some(ExtraAttributes(extra_attr))
+    }
+    fn display(&self) -> Option<&'a NbtCompound> {
+        let tag = nbt_use!(self.0, "tag", Compound)?;
+        nbt_use!(tag, "display", Compound)
+    }
+    pub fn display_name(&self) -> Option<String> {
+        let name = nbt_use!(self.display()?, "Name", String)?;
+        Some(name.to_string_lossy().into_owned())
+    }
+    pub fn lore(&self) -> Option<Vec<String>> {
+        let lore = nbt_use!(self.display()?, "Lore", List)?;
+        Some(
+            lore.strings()?
+                .iter()
+                .map(|it| it.to_string_lossy().into_owned())
+                .collect(),
+        )
+    }
+}
+
+pub struct ExtraAttributes<'a>(&'a NbtCompound);
+
+impl<'a> ExtraAttributes<'a> {
+    pub fn id(&self) -> Option<Arc<str>> {
+        let str = nbt_use!(self.0, "id", String)?;
+        Some(str.to_str().into())
+    }
+    pub fn uuid(&self) -> Option<String> {
+        let str = nbt_use!(self.0, "uuid", String)?;
+        Some(str.to_string_lossy().into_owned())
+    }
+    pub fn enchantments(&self) -> Option<BTreeMap<String, i32>> {
+        let enchantments = nbt_use!(self.0, "enchantments", Compound)?;
+        Some(
+            enchantments
+                .iter()
+                .filter_map(|(name, level)| {
+                    Some((name.to_string_lossy().into_owned(), level.int()?))
+                })
+                .collect(),
+        )
+    }
+}
@@ -1,21 +1,22 @@
 use crate::global_application_config;
-use crate::util::{MillisecondTimestamp, UrlForRequest};
-use base64::Engine;
-use futures::{AsyncReadExt, StreamExt};
+use crate::item::{decode_item_stack, ItemStack};
+use crate::util::{MillisecondTimestamp, Shutdown, UrlForRequest};
+use anyhow::Context as _;
+use futures::StreamExt;
 use hyper::{Body, Method, Request, StatusCode};
 use influxdb::InfluxDbWriteable;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use simdnbt::owned::{BaseNbt, NbtCompound, NbtTag};
-use std::collections::HashMap;
-use std::io::Cursor;
-use std::io::Read;
+use simdnbt::owned::NbtCompound;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::task::JoinHandle;
-use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, span, warn, Level};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use url::Url;
 use uuid::Uuid;
 
@@ -57,83 +58,41 @@ struct Auction {
     category: S,
 }
 
-macro_rules! nbt_use {
-    ($o:expr, $n:expr, $t:ident) => {
-        match ::simdnbt::owned::NbtCompound::get($o, $n) {
-            ::std::option::Option::Some(::simdnbt::owned::NbtTag::$t(it)) => {
-                ::std::option::Option::Some(it)
-            }
-            _ => return ::std::option::Option::None,
-        }
-    };
-}
-struct ItemStack<'a>(&'a NbtCompound);
-
-impl<'a> ItemStack<'a> {
-    fn new(compound: &'a NbtCompound) -> Self {
-        Self(compound)
-    }
-    pub fn extra_attributes(&self) -> Option<ExtraAttributes<'a>> {
-        let tag = nbt_use!(self.0, "tag", Compound)?;
-        let extra_attr = nbt_use!(tag, "ExtraAttributes", Compound)?;
-        Some(ExtraAttributes(extra_attr))
+impl AuctionPage {
+    /// Whether this page only contains auctions that did not change since the given scan.
+    fn is_stale(&self, last_full_scan: Option<MillisecondTimestamp>) -> bool {
+        last_full_scan.is_some()
+            && !self
+                .auctions
+                .iter()
+                .any(|it| it.needs_processing(last_full_scan))
     }
 }
-struct ExtraAttributes<'a>(&'a NbtCompound);
 
-impl<'a> ExtraAttributes<'a> {
-    pub fn id(&self) -> Option<S> {
-        let str = nbt_use!(self.0, "id", String)?;
-        Some(str.to_str().into())
+impl Auction {
+    fn as_active_bin(&self, buckets: A<S>) -> ActiveBin {
+        ActiveBin {
+            buckets,
+            price: self.starting_bid,
+            end: self.end,
+        }
     }
-}
 
-impl Auction {
     fn needs_processing(&self, last_full_scan: Option<MillisecondTimestamp>) -> bool {
         match (self.last_updated, last_full_scan) {
-            (Some(auction), Some(scan)) => auction < scan,
+            (Some(auction), Some(scan)) => auction >= scan,
             _ => true,
         }
     }
 
-    #[tracing::instrument(skip_all)]
-    fn item_bytes(&self) -> anyhow::Result<A<u8>> {
-        let base64_decoded = base64::engine::general_purpose::STANDARD
-            .decode(self.item_bytes_compressed.as_ref().as_bytes())?;
-
-        Ok(base64_decoded.into())
-    }
-    #[tracing::instrument(skip_all)]
-    pub async fn raw_nbt(&self) -> anyhow::Result<BaseNbt> {
-        let mut ungzipped = Vec::new();
-        let input = self.item_bytes()?;
-        let mut decoder = flate2::read::GzDecoder::new(input.as_ref());
-        decoder.read_to_end(&mut ungzipped)?;
-        let mut c: Cursor<&[u8]> = Cursor::new(ungzipped.as_slice());
-        let tag = simdnbt::owned::read(&mut c)?;
-        Ok(tag.unwrap())
-    }
-    async fn item_stack(&self) -> anyhow::Result<NbtCompound> {
-        let nbt = self.raw_nbt().await?;
-        match nbt.as_compound().take("i") {
-            None => anyhow::bail!("Missing root i tag"),
-            Some(NbtTag::List(list)) => {
-                let tag = list
-                    .into_compounds()
-                    .ok_or(anyhow::anyhow!("Expected compound tag"))?
-                    .swap_remove(0);
-                Ok(tag)
-            }
-            _ => {
-                // TODO: 'a borrow a lot of things
-                anyhow::bail!("Misshapen root tag");
-            }
-        }
+    fn item_stack(&self) -> anyhow::Result<NbtCompound> {
+        decode_item_stack(&self.item_bytes_compressed)
     }
 }
 
+/// Fetch an auction page, storing it in the record directory if `record` is set.
 #[tracing::instrument]
-async fn request_ah_page(page_number: u32) -> anyhow::Result<AuctionPage> {
+async fn request_ah_page(page_number: u32, record: bool) -> anyhow::Result<AuctionPage> {
     let args = [("page", format!("{page_number}"))];
     let url = Url::parse_with_params("https://api.hypixel.net/v2/skyblock/auctions", args)?;
     let request = Request::builder()
@@ -147,108 +106,525 @@ async fn request_ah_page(page_number: u32) -> anyhow::Result<AuctionPage> {
     }
     let buffer = hyper::body::to_bytes(response.into_body()).await?;
     let page: AuctionPage = serde_json::from_slice(&buffer)?;
+    if let Some(directory) = global_application_config
+        .ah_record_directory
+        .as_ref()
+        .filter(|_| record)
+    {
+        if let Err(err) = record_ah_page(directory, &page, &buffer).await {
+            warn!(%err, "Could not record auction page {page_number}");
+        }
+    }
     Ok(page)
 }
-/// Returns the timestamp that this update was processed
+
+/// Store the raw response of an auction page, so that it can later be fed back through
+/// [replay_snapshots]. Pages are grouped by the `lastUpdated` of the snapshot they belong to. Only
+/// full scans are recorded, as incremental scans stop at the first page without updates.
+async fn record_ah_page(directory: &Path, page: &AuctionPage, raw: &[u8]) -> anyhow::Result<()> {
+    let snapshot = page.last_updated.map_or(0, |it| it.0);
+    let directory = directory.join(snapshot.to_string());
+    tokio::fs::create_dir_all(&directory).await?;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(raw)?;
+    let compressed = encoder.finish()?;
+    tokio::fs::write(directory.join(format!("{}.json.gz", page.page)), compressed).await?;
+    Ok(())
+}
+
+/// Load all recorded pages of a snapshot created by [record_ah_page], ordered by page number.
+/// Snapshots missing any of their pages are rejected, for example if the snapshot changed halfway
+/// through a scan.
+async fn load_snapshot(directory: &Path) -> anyhow::Result<Vec<AuctionPage>> {
+    let mut pages = vec![];
+    let mut files = tokio::fs::read_dir(directory).await?;
+    while let Some(file) = files.next_entry().await? {
+        let path = file.path();
+        if !path.to_string_lossy().ends_with(".json.gz") {
+            continue;
+        }
+        let compressed = tokio::fs::read(&path).await?;
+        let mut buffer = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice()).read_to_end(&mut buffer)?;
+        let page: AuctionPage = serde_json::from_slice(&buffer)
+            .with_context(|| format!("Could not parse recorded page {}", path.display()))?;
+        pages.push(page);
+    }
+    pages.sort_by_key(|it| it.page);
+    let total_pages = pages.first().map_or(0, |it| it.total_pages);
+    if total_pages == 0 {
+        anyhow::bail!("Snapshot does not contain any pages");
+    }
+    if let Some(page) = pages.iter().find(|it| it.total_pages != total_pages) {
+        anyhow::bail!(
+            "Page {} reports {} total pages instead of {total_pages}",
+            page.page,
+            page.total_pages
+        );
+    }
+    if let Some((expected, page)) = pages
+        .iter()
+        .enumerate()
+        .find(|(index, page)| page.page != *index as u32)
+    {
+        anyhow::bail!(
+            "Snapshot is missing page {expected}, found page {}",
+            page.page
+        );
+    }
+    if pages.len() != total_pages as usize {
+        anyhow::bail!("Snapshot contains {} of {total_pages} pages", pages.len());
+    }
+    Ok(pages)
+}
+
+/// Feed recorded snapshots through the same processing as a live scan. Snapshots are processed
+/// in the order they are given, each one as if it was a fresh full scan.
+pub(crate) async fn replay_snapshots(
+    snapshots: &[PathBuf],
+    sink: &PriceSink,
+) -> anyhow::Result<()> {
+    let token = CancellationToken::new();
+    for snapshot in snapshots {
+        let pages = load_snapshot(snapshot)
+            .await
+            .with_context(|| format!("Could not load snapshot {}", snapshot.display()))?;
+        let Some(timestamp) = pages.first().and_then(|it| it.last_updated) else {
+            anyhow::bail!(
+                "Snapshot {} does not have a lastUpdated",
+                snapshot.display()
+            );
+        };
+        let mut state = ScanState::default();
+        for page in &pages {
+            state
+                .active
+                .extend(process_page(page, None, &HashMap::new(), &token).await?);
+        }
+        info!(
+            "Replaying snapshot {} with {} pages",
+            snapshot.display(),
+            pages.len()
+        );
+        update_prices(sink, timestamp, state.prices(), &HashMap::new()).await?;
+    }
+    Ok(())
+}
+
+/// Returned when a scan got interrupted by the cancellation token.
+#[derive(Debug)]
+struct ScanCancelled;
+
+impl std::fmt::Display for ScanCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Auction scan cancelled")
+    }
+}
+
+impl std::error::Error for ScanCancelled {}
+
+async fn cancellable<T>(
+    token: &CancellationToken,
+    future: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::select! {
+        _ = token.cancelled() => Err(ScanCancelled.into()),
+        it = future => it,
+    }
+}
+
+const PAGE_RETRIES: u32 = 3;
+
+async fn request_ah_page_retrying(
+    page_number: u32,
+    record: bool,
+    token: &CancellationToken,
+) -> anyhow::Result<AuctionPage> {
+    let mut attempt = 0;
+    loop {
+        match cancellable(token, request_ah_page(page_number, record)).await {
+            Ok(page) => return Ok(page),
+            Err(err) if err.is::<ScanCancelled>() || attempt >= PAGE_RETRIES => return Err(err),
+            Err(err) => {
+                attempt += 1;
+                let backoff = Duration::from_secs(1 << attempt);
+                warn!(%err, "Could not fetch auction page {page_number}, retrying in {backoff:?} ({attempt}/{PAGE_RETRIES})");
+                cancellable(token, async {
+                    tokio::time::sleep(backoff).await;
+                    Ok(())
+                })
+                .await?;
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct EndedAuction {
+    auction_id: Uuid,
+}
+
+#[derive(Deserialize, Debug)]
+struct EndedAuctionsPage {
+    auctions: A<EndedAuction>,
+}
+
 #[tracing::instrument]
-async fn item_ah_scan_fallible(
-    // TODO: inherit cancellation token
+async fn request_ended_auctions() -> anyhow::Result<EndedAuctionsPage> {
+    let request = Request::builder()
+        .uri("https://api.hypixel.net/v2/skyblock/auctions_ended")
+        .method(Method::GET)
+        .body(Body::empty())?;
+    let response = global_application_config.client.request(request).await?;
+    let buffer = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&buffer)?)
+}
+
+/// A BIN auction that was seen during a previous scan.
+struct ActiveBin {
+    buckets: A<S>,
+    price: f64,
+    end: MillisecondTimestamp,
+}
+
+/// How many incremental scans can happen in a row before a full scan is forced to correct drift
+/// (for example from sold auctions that fell out of the `auctions_ended` window).
+const INCREMENTAL_SCANS_PER_FULL_SCAN: u32 = 15;
+
+/// The auctions known from previous scans, so that later scans only need to fetch the pages
+/// containing auctions updated since [ScanState::last_full_scan].
+#[derive(Default)]
+struct ScanState {
+    /// The `lastUpdated` of the snapshot up to which [ScanState::active] is complete.
     last_full_scan: Option<MillisecondTimestamp>,
+    incremental_scans: u32,
+    active: HashMap<Uuid, ActiveBin>,
+}
+
+impl ScanState {
+    fn needs_full_scan(&self) -> bool {
+        self.last_full_scan.is_none() || self.incremental_scans >= INCREMENTAL_SCANS_PER_FULL_SCAN
+    }
+
+    fn prices(&self) -> impl Iterator<Item = (&A<S>, f64)> {
+        self.active.values().map(|it| (&it.buckets, it.price))
+    }
+}
+
+/// Returns the timestamp that this update was processed
+#[tracing::instrument(skip_all)]
+async fn item_ah_scan_fallible(
+    state: &mut ScanState,
+    token: &CancellationToken,
 ) -> anyhow::Result<MillisecondTimestamp> {
-    // Also request https://api.hypixel.net/v2/skyblock/auctions_ended
-    // For ended auctions
-    let initial_page = request_ah_page(0).await?;
-
-    let mut all_prices: Vec<(A<S>, f64)> = vec![];
-    all_prices.extend(
-        process_page(&initial_page, last_full_scan)
-            .await?
-            .into_iter(),
-    );
-    let pages =
-        futures::stream::iter((1..initial_page.total_pages).map(|page| request_ah_page(page)))
-            .buffer_unordered(8)
-            .collect::<Vec<_>>()
-            .await;
-    info!("Web requests completed");
-    for page in pages {
-        let page = page?;
-        all_prices.extend(process_page(&page, last_full_scan).await?.into_iter());
+    let full_scan = state.needs_full_scan();
+    let since = if full_scan {
+        None
+    } else {
+        state.last_full_scan
+    };
+    let (snapshot, found) = async {
+        let initial_page = request_ah_page_retrying(0, full_scan, token).await?;
+        let snapshot = initial_page
+            .last_updated
+            .ok_or(anyhow::anyhow!("initial page does not have a lastUpdated"))?;
+
+        let mut found = process_page(&initial_page, since, &state.active, token).await?;
+        if !initial_page.is_stale(since) {
+            // Pages are ordered by recency, so fetch in order and stop at the first page without
+            // any updated auctions. Dropping the stream discards requests that are still in flight.
+            let mut pages = futures::stream::iter(
+                (1..initial_page.total_pages)
+                    .map(|page| request_ah_page_retrying(page, full_scan, token)),
+            )
+            .buffered(8);
+            while let Some(page) = pages.next().await {
+                let page = page?;
+                found.extend(process_page(&page, since, &state.active, token).await?);
+                if page.is_stale(since) {
+                    debug!("Stopping scan early at stale page {}", page.page);
+                    break;
+                }
+            }
+        }
+        anyhow::Ok((snapshot, found))
     }
-    info!("Prices aggregated.");
+    .instrument(info_span!("fetch_pages", full_scan))
+    .await?;
+    info!("Web requests completed");
+
+    let ended = cancellable(token, request_ended_auctions()).await?;
+    let (prices, volume) = info_span!("aggregate").in_scope(|| {
+        let mut volume = HashMap::<S, u64>::new();
+        for auction in &*ended.auctions {
+            if let Some(bin) = state.active.remove(&auction.auction_id) {
+                for bucket in bin.buckets.iter() {
+                    *volume.entry(bucket.clone()).or_default() += 1;
+                }
+            }
+        }
+        if full_scan {
+            state.active.clear();
+            state.incremental_scans = 0;
+        } else {
+            state.active.retain(|_, it| it.end > snapshot);
+            state.incremental_scans += 1;
+        }
+        state.active.extend(found);
+        state.last_full_scan = Some(snapshot);
+        info!(
+            "Prices aggregated from {} active auctions ({}).",
+            state.active.len(),
+            if full_scan { "full" } else { "incremental" }
+        );
+        (state.prices(), volume)
+    });
 
-    update_prices(&all_prices).await?;
-    Ok(initial_page
-        .last_updated
-        .ok_or(anyhow::anyhow!("initial page does not have a lastUpdated"))?)
+    update_prices(
+        &PriceSink::influx(),
+        MillisecondTimestamp::now()?,
+        prices,
+        &volume,
+    )
+    .await?;
+    Ok(snapshot)
 }
 
 #[derive(InfluxDbWriteable)]
 struct PricePoint {
     time: MillisecondTimestamp,
-    price: f64,
+    price: Option<f64>,
+    second_lowest: Option<f64>,
+    p10: Option<f64>,
+    p25: Option<f64>,
+    median: Option<f64>,
+    fair_price: Option<f64>,
+    listings: u64,
+    volume: u64,
     #[influxdb(tag)]
     id: String, // TODO: ref this
 }
 
-async fn update_prices(all_prices: &[(impl AsRef<[S]>, f64)]) -> anyhow::Result<()> {
-    let mut prices = HashMap::<S, _>::new();
+/// Listings below this fraction of the 25th percentile are considered mispriced and are ignored
+/// for [ListingPrices::fair_price].
+const FAIR_PRICE_OUTLIER_FACTOR: f64 = 0.5;
+
+/// Statistics over the active BIN listings and recent sales of one bucket.
+#[derive(Serialize, Debug, Clone, Copy)]
+struct PriceStatistics {
+    /// Absent if the bucket has sales but no remaining listings.
+    #[serde(flatten)]
+    prices: Option<ListingPrices>,
+    listings: u64,
+    /// Tracked listings that sold since the previous scan.
+    volume: u64,
+}
+
+/// Prices of the active BIN listings of one bucket.
+#[derive(Serialize, Debug, Clone, Copy)]
+struct ListingPrices {
+    /// The lowest BIN
+    price: f64,
+    second_lowest: Option<f64>,
+    p10: f64,
+    p25: f64,
+    median: f64,
+    /// The lowest BIN that is not an outlier compared to the rest of the listings.
+    fair_price: f64,
+}
+
+/// Linearly interpolated percentile of an ascending, non-empty slice.
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    let position = fraction * (sorted.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (position - lower as f64)
+}
+
+impl PriceStatistics {
+    fn from_listings(mut listings: Vec<f64>, volume: u64) -> Self {
+        if listings.is_empty() {
+            return PriceStatistics {
+                prices: None,
+                listings: 0,
+                volume,
+            };
+        }
+        listings.sort_by(f64::total_cmp);
+        let p25 = percentile(&listings, 0.25);
+        let fair_price = listings
+            .iter()
+            .copied()
+            .find(|it| *it >= p25 * FAIR_PRICE_OUTLIER_FACTOR)
+            .unwrap_or(listings[0]);
+        PriceStatistics {
+            prices: Some(ListingPrices {
+                price: listings[0],
+                second_lowest: listings.get(1).copied(),
+                p10: percentile(&listings, 0.10),
+                p25,
+                median: percentile(&listings, 0.5),
+                fair_price,
+            }),
+            listings: listings.len() as u64,
+            volume,
+        }
+    }
+}
+
+/// Destination for aggregated prices.
+pub(crate) enum PriceSink {
+    Influx(influxdb::Client),
+    /// Write one JSON line per update to stdout, with buckets in a stable order. Used to diff
+    /// the output of replayed snapshots.
+    Stdout,
+}
+
+impl PriceSink {
+    pub(crate) fn influx() -> Self {
+        Self::Influx(influxdb::Client::new(
+            &global_application_config.influx_url,
+            "prices",
+        ))
+    }
+}
+
+#[derive(Serialize)]
+struct PriceUpdate<'a> {
+    time: MillisecondTimestamp,
+    prices: &'a BTreeMap<S, PriceStatistics>,
+}
+
+fn aggregate_prices<'a>(
+    all_prices: impl IntoIterator<Item = (&'a A<S>, f64)>,
+    volume: &HashMap<S, u64>,
+) -> BTreeMap<S, PriceStatistics> {
+    let mut listings = BTreeMap::<S, Vec<f64>>::new();
     for (buckets, price) in all_prices {
-        for bucket in buckets.as_ref().iter() {
-            let original = prices.entry(bucket.clone()).or_insert(*price);
-            *original = original.min(*price)
+        for bucket in buckets.iter() {
+            listings.entry(bucket.clone()).or_default().push(price);
         }
     }
-    let ts = MillisecondTimestamp::now()?;
-    let influx = influxdb::Client::new(&global_application_config.influx_url, "prices");
-    let readings: Vec<_> = prices
+    // Buckets whose last listings sold since the previous scan still get their volume reported.
+    for bucket in volume.keys() {
+        listings.entry(bucket.clone()).or_default();
+    }
+    listings
         .into_iter()
-        .map(|(k, v)| {
-            PricePoint {
-                time: ts,
-                price: v,
-                id: (*k).to_owned(),
-            }
-            .into_query("lowest_bin")
+        .map(|(bucket, listings)| {
+            let volume = volume.get(&bucket).copied().unwrap_or(0);
+            (bucket, PriceStatistics::from_listings(listings, volume))
         })
-        .collect();
-    let res = influx.query(readings).await?;
-    info!("Prices updated in influx: {res}");
+        .collect()
+}
+
+#[tracing::instrument(skip_all)]
+async fn update_prices<'a>(
+    sink: &PriceSink,
+    ts: MillisecondTimestamp,
+    all_prices: impl IntoIterator<Item = (&'a A<S>, f64)>,
+    volume: &HashMap<S, u64>,
+) -> anyhow::Result<()> {
+    let prices = aggregate_prices(all_prices, volume);
+    match sink {
+        PriceSink::Influx(influx) => {
+            let readings: Vec<_> = prices
+                .into_iter()
+                .map(|(k, v)| {
+                    PricePoint {
+                        time: ts,
+                        price: v.prices.map(|it| it.price),
+                        second_lowest: v.prices.and_then(|it| it.second_lowest),
+                        p10: v.prices.map(|it| it.p10),
+                        p25: v.prices.map(|it| it.p25),
+                        median: v.prices.map(|it| it.median),
+                        fair_price: v.prices.map(|it| it.fair_price),
+                        listings: v.listings,
+                        volume: v.volume,
+                        id: (*k).to_owned(),
+                    }
+                    .into_query("lowest_bin")
+                })
+                .collect();
+            let res = influx.query(readings).await?;
+            info!("Prices updated in influx: {res}");
+        }
+        PriceSink::Stdout => {
+            let update = PriceUpdate {
+                time: ts,
+                prices: &prices,
+            };
+            println!("{}", serde_json::to_string(&update)?);
+        }
+    }
     Ok(())
 }
 
+/// How many auctions get decoded by one blocking task.
+const DECODE_CHUNK_SIZE: usize = 64;
+/// How many blocking decode tasks may run at once.
+const DECODE_CONCURRENCY: usize = 8;
+
+/// Collect the BIN auctions of a page that changed since the last scan. Items of auctions that
+/// are already in `known` are not decoded again, since the item of an auction never changes.
 #[tracing::instrument(skip_all)]
 async fn process_page(
     page: &AuctionPage,
     last_full_scan: Option<MillisecondTimestamp>,
-) -> anyhow::Result<Vec<(A<S>, f64)>> {
+    known: &HashMap<Uuid, ActiveBin>,
+    token: &CancellationToken,
+) -> anyhow::Result<Vec<(Uuid, ActiveBin)>> {
     let mut v = vec![];
-    for auction in &*page.auctions {
-        if !auction.needs_processing(last_full_scan) {
+    let mut to_decode = vec![];
+    for (index, auction) in page.auctions.iter().enumerate() {
+        if !auction.bin || !auction.needs_processing(last_full_scan) {
+            // Eternal death upon auctions (at least until i get around to parsing recently ended auctions)
             continue;
         }
-        match auction.item_stack().await {
-            Ok(item_stack) => {
-                let bucket = find_buckets(&ItemStack::new(&item_stack));
-                if auction.bin {
-                    v.push((bucket, auction.starting_bid));
-                } else {
-                    // Eternal death upon auctions (at least until i get around to parsing recently ended auctions)
-                }
-            }
-            Err(err) => {
-                error!(
-                    %err,
-                    "Could not parse item with auction id {}: {:?}",
-                    auction.uuid,
-                    auction.raw_nbt().await
-                );
-            }
+        match known.get(&auction.uuid) {
+            Some(bin) => v.push((auction.uuid, auction.as_active_bin(bin.buckets.clone()))),
+            None => to_decode.push(index),
+        }
+    }
+    let chunks = to_decode
+        .chunks(DECODE_CHUNK_SIZE)
+        .map(<[usize]>::to_vec)
+        .collect::<Vec<_>>();
+    let auctions = page.auctions.clone();
+    let mut decoded = futures::stream::iter(chunks)
+        .map(move |chunk| {
+            let auctions = auctions.clone();
+            tokio::task::spawn_blocking(move || {
+                chunk
+                    .into_iter()
+                    .filter_map(|index| {
+                        let auction = &auctions[index];
+                        let buckets = decode_buckets(auction)?;
+                        Some((auction.uuid, auction.as_active_bin(buckets)))
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .buffer_unordered(DECODE_CONCURRENCY);
+    while let Some(chunk) = decoded.next().await {
+        if token.is_cancelled() {
+            return Err(ScanCancelled.into());
         }
+        v.extend(chunk?);
     }
     Ok(v)
 }
 
+fn decode_buckets(auction: &Auction) -> Option<A<S>> {
+    match auction.item_stack() {
+        Ok(item_stack) => Some(find_buckets(&ItemStack::new(&item_stack))),
+        Err(err) => {
+            error!(%err, "Could not parse item with auction id {}", auction.uuid);
+            None
+        }
+    }
+}
+
 fn find_buckets(stack: &ItemStack) -> A<S> {
     let Some(attr) = &stack.extra_attributes() else {
         return [].into();
@@ -256,15 +632,38 @@ fn find_buckets(stack: &ItemStack) -> A<S> {
     let Some(id) = &attr.id() else {
         return [].into();
     };
-    let mut ids: Vec<S> = vec![];
-    ids.push(id.clone());
+    let ids: Vec<S> = vec![id.clone()];
     ids.into()
 }
 
-async fn item_ah_scan(last_full_scan: &mut Option<MillisecondTimestamp>) -> Duration {
-    match item_ah_scan_fallible(*last_full_scan).await {
+/// Millisecond timestamp of the last successful scan, or of the loop start before the first scan
+/// finished. 0 if the loop is not running.
+static LAST_SUCCESSFUL_SCAN: AtomicU64 = AtomicU64::new(0);
+
+/// The time since the last successful auction scan, or since the loop started if no scan finished
+/// yet. [None] if the loop is not running.
+pub(crate) fn time_since_last_scan() -> Option<Duration> {
+    match LAST_SUCCESSFUL_SCAN.load(Ordering::SeqCst) {
+        0 => None,
+        last => Some(Duration::from_millis(
+            MillisecondTimestamp::from(SystemTime::now())
+                .0
+                .saturating_sub(last),
+        )),
+    }
+}
+
+fn reset_scan_age() {
+    LAST_SUCCESSFUL_SCAN.store(
+        MillisecondTimestamp::from(SystemTime::now()).0,
+        Ordering::SeqCst,
+    );
+}
+
+async fn item_ah_scan(state: &mut ScanState, token: &CancellationToken) -> Duration {
+    match item_ah_scan_fallible(state, token).await {
         Ok(timestamp) => {
-            *last_full_scan = Some(timestamp);
+            reset_scan_age();
             let d = Duration::from_secs(70); // 60 seconds update interval + 10 seconds lenience
             let w = timestamp + d;
             let c = w.wait_time_or_zero();
@@ -274,6 +673,10 @@ async fn item_ah_scan(last_full_scan: &mut Option<MillisecondTimestamp>) -> Dura
             );
             c
         }
+        Err(er) if er.is::<ScanCancelled>() => {
+            info!("Scan cancelled, keeping state of the last completed scan");
+            Duration::ZERO
+        }
         Err(er) => {
             error!(%er, "Encountered error during scanning",);
             Duration::from_secs(30)
@@ -282,14 +685,16 @@ async fn item_ah_scan(last_full_scan: &mut Option<MillisecondTimestamp>) -> Dura
 }
 
 #[tracing::instrument(skip_all)]
-async fn loop_body(cancellation_token: CancellationToken) {
+async fn loop_body(shutdown: Shutdown) {
     info!("Auction house collection loop started.");
     debug!("Debug logging is enabled.");
     let mut wait_time = Duration::ZERO;
-    let mut last_full_scan = None;
+    let mut state = ScanState::default();
+    reset_scan_age();
     loop {
         tokio::select! {
-            _ = cancellation_token.cancelled() => {
+            biased;
+            _ = shutdown.requested.cancelled() => {
                 info!("Exiting ah loop");
                 return
             }
@@ -297,21 +702,90 @@ async fn loop_body(cancellation_token: CancellationToken) {
                 info!("Waited {wait_time:?} for next loop")
             }
         }
-        tokio::select! {
-            _ = cancellation_token.cancelled() => {
-                warn!("Exiting ah loop during eval");
-                return
-            }
-              it = item_ah_scan(&mut last_full_scan) => {
-                wait_time = it
-            }
-        }
+        // A scan that is running during shutdown gets finished, unless it exceeds the deadline
+        wait_time = item_ah_scan(&mut state, &shutdown.deadline).await;
     }
 }
 
-pub(crate) fn start_loop(cancellation_token: &CancellationToken) -> JoinHandle<()> {
-    let token = cancellation_token.clone();
+pub(crate) fn start_loop(shutdown: &Shutdown) -> JoinHandle<()> {
+    let shutdown = shutdown.clone();
     tokio::spawn(async move {
-        loop_body(token).await;
+        loop_body(shutdown).await;
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buckets(names: &[&str]) -> A<S> {
+        names.iter().map(|it| S::from(*it)).collect()
+    }
+
+    #[test]
+    fn interpolates_percentiles() {
+        let sorted = [1.0, 3.0, 5.0, 10.0, 100.0];
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&sorted, 0.1), 1.8);
+        assert_eq!(percentile(&sorted, 0.25), 3.0);
+        assert_eq!(percentile(&sorted, 0.5), 5.0);
+        assert_eq!(percentile(&sorted, 0.875), 55.0);
+        assert_eq!(percentile(&sorted, 1.0), 100.0);
+        assert_eq!(percentile(&[7.0], 0.5), 7.0);
+    }
+
+    #[test]
+    fn computes_statistics_of_unsorted_listings() {
+        let statistics = PriceStatistics::from_listings(vec![10.0, 1.0, 100.0, 5.0, 3.0], 4);
+        let prices = statistics.prices.unwrap();
+        assert_eq!(prices.price, 1.0);
+        assert_eq!(prices.second_lowest, Some(3.0));
+        assert_eq!(prices.p10, 1.8);
+        assert_eq!(prices.p25, 3.0);
+        assert_eq!(prices.median, 5.0);
+        assert_eq!(prices.fair_price, 3.0);
+        assert_eq!(statistics.listings, 5);
+        assert_eq!(statistics.volume, 4);
+
+        let single = PriceStatistics::from_listings(vec![7.0], 0).prices.unwrap();
+        assert_eq!((single.price, single.second_lowest), (7.0, None));
+        assert_eq!(
+            (single.p10, single.median, single.fair_price),
+            (7.0, 7.0, 7.0)
+        );
+    }
+
+    #[test]
+    fn fair_price_skips_outliers() {
+        let prices = PriceStatistics::from_listings(vec![1.0, 100.0, 100.0, 100.0], 0)
+            .prices
+            .unwrap();
+        assert_eq!(prices.price, 1.0);
+        assert_eq!(prices.p25, 75.25);
+        assert_eq!(prices.fair_price, 100.0);
+    }
+
+    #[test]
+    fn aggregates_listings_and_volume_per_bucket() {
+        let first = buckets(&["a", "b"]);
+        let second = buckets(&["a"]);
+        let volume = HashMap::from([(S::from("a"), 1), (S::from("c"), 2)]);
+        let prices = aggregate_prices([(&first, 20.0), (&second, 10.0)], &volume);
+        assert_eq!(
+            prices.keys().map(|it| &**it).collect::<Vec<_>>(),
+            ["a", "b", "c"]
+        );
+        let a = prices["a"];
+        assert_eq!((a.listings, a.volume), (2, 1));
+        assert_eq!(a.prices.unwrap().price, 10.0);
+        assert_eq!(a.prices.unwrap().second_lowest, Some(20.0));
+        let b = prices["b"];
+        assert_eq!((b.listings, b.volume), (1, 0));
+        let c = prices["c"];
+        assert!(c.prices.is_none());
+        assert_eq!(
+            serde_json::to_value(c).unwrap(),
+            serde_json::json!({"listings": 0, "volume": 2})
+        );
+    }
+}
@@ -15,23 +15,20 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-#![feature(lazy_cell)]
 #![feature(adt_const_params)]
 #![allow(incomplete_features)]
 extern crate core;
 
 use std::env;
 use std::net::{IpAddr, SocketAddr};
-use std::str::FromStr as _;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::hypixel::Rule;
 use crate::meta::respond_to_meta;
-use crate::util::{MillisecondTimestamp, Obscure};
-use anyhow::Context as _;
+use crate::util::{MillisecondTimestamp, Obscure, Shutdown};
 use clap::Parser;
-use futures::SinkExt;
-use hmac::digest::KeyInit;
 use hmac::Hmac;
 use hyper::client::HttpConnector;
 use hyper::service::{make_service_fn, service_fn};
@@ -40,12 +37,19 @@ use hyper_tls::HttpsConnector;
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
-use tracing::instrument::WithSubscriber;
-use tracing::{error, info, warn};
+use tracing::{error, error_span, info, warn, Instrument};
 
+pub mod access_log;
+pub mod config;
 pub mod hypixel;
+pub mod item;
 pub mod meta;
+pub mod metrics;
 pub mod mojang;
+pub mod nbt;
+pub mod statistics;
+pub mod storage;
+pub mod telemetry;
 pub mod util;
 
 pub mod built_info {
@@ -58,10 +62,14 @@ pub mod neu;
 #[cfg(feature = "lbin")]
 pub mod lbin;
 
+#[cfg(feature = "bazaar")]
+pub mod bazaar;
+
 #[derive(Debug)]
 pub struct RequestContext {
-    redis_client: Obscure<redis::aio::ConnectionManager, "ConnectionManager">,
+    storage: Arc<dyn storage::Storage>,
     request: Request<Body>,
+    access: access_log::AccessRecord,
 }
 
 #[derive(Debug)]
@@ -71,15 +79,30 @@ pub struct GlobalApplicationContext {
     address: IpAddr,
     port: u16,
     rules: Vec<Rule>,
+    rule_trie: hypixel::RuleTrie,
     allow_anonymous: bool,
     // Use sha384 to prevent against length extension attacks
     key: Hmac<sha2::Sha384>,
-    redis_url: Obscure<String>,
+    storage: storage::StorageBackend,
     default_token_duration: Duration,
     rate_limit_lifespan: Duration,
     rate_limit_bucket: u64,
+    /// How long to keep serving after reporting as not ready, before the listener is closed
+    shutdown_grace_period: Duration,
+    /// How long in-flight requests and background scans may take to finish during shutdown
+    shutdown_timeout: Duration,
+    access_log: Option<access_log::AccessLogTarget>,
+    tracing_layers: Vec<telemetry::TracingLayer>,
+    /// The OpenTelemetry collector spans are sent to, via gRPC
+    #[cfg(feature = "otlp")]
+    otlp_endpoint: String,
     #[cfg(feature = "influxdb")]
     influx_url: String,
+    /// How often request metrics get written to InfluxDB. Zero disables the export
+    #[cfg(feature = "influxdb")]
+    stats_export_interval: Duration,
+    #[cfg(feature = "lbin")]
+    ah_record_directory: Option<PathBuf>,
 }
 
 fn make_error(status_code: u16, error_text: &str) -> anyhow::Result<Response<Body>> {
@@ -90,15 +113,22 @@ fn make_error(status_code: u16, error_text: &str) -> anyhow::Result<Response<Bod
 
 async fn respond_to(mut context: RequestContext) -> anyhow::Result<Response<Body>> {
     let path = &context.request.uri().path().to_owned();
+    // Probes need to work while redis is unavailable, and are not client traffic anyway
+    if let Some(probe) = path
+        .strip_prefix("/_meta/")
+        .filter(|it| *it == "health" || *it == "ready")
+    {
+        return respond_to_meta(context, probe).await;
+    }
     let user_agent = context
         .request
         .headers()
         .get("user-agent")
         .map_or_else(|| Ok("none"), |x| x.to_str())?
         .to_owned();
-    redis::Cmd::zincr("user-agent", user_agent, 1)
-        .query_async(&mut context.redis_client.0)
-        .await?;
+    if let Err(err) = statistics::record_client(context.storage.as_ref(), &user_agent).await {
+        warn!(%err, "Could not record user agent");
+    }
     if path == "/" {
         return Ok(Response::builder()
             .status(302)
@@ -116,6 +146,21 @@ async fn respond_to(mut context: RequestContext) -> anyhow::Result<Response<Body
         }
     }
 
+    if let Some(nbt_path) = path.strip_prefix("/v1/nbt/") {
+        let (save, _principal) = require_login!(context);
+        if let Some(resp) = nbt::respond_to(&mut context, nbt_path).await? {
+            return save.save_to(resp);
+        }
+    }
+
+    #[cfg(feature = "bazaar")]
+    if let Some(bazaar_path) = path.strip_prefix("/v1/bazaar/") {
+        let (save, _principal) = require_login!(context);
+        if let Some(resp) = bazaar::respond_to(bazaar_path).await? {
+            return save.save_to(resp);
+        }
+    }
+
     #[cfg(feature = "neu")]
     if let Some(neu_path) = path.strip_prefix("/v1/neu/") {
         let (save, principal) = require_login!(context);
@@ -128,15 +173,28 @@ async fn respond_to(mut context: RequestContext) -> anyhow::Result<Response<Body
 }
 
 async fn wrap_error(context: RequestContext) -> anyhow::Result<Response<Body>> {
+    let summary = access_log::RequestSummary {
+        request_id: access_log::request_id(&context.request),
+        method: context.request.method().to_string(),
+        path: context.request.uri().path().to_owned(),
+    };
+    // At error level, so that the request id is attached to every event that gets logged at all
+    let span = error_span!(
+        "request",
+        id = %summary.request_id,
+        method = %summary.method,
+        path = %summary.path
+    );
+    let access = context.access.clone();
     let start = Instant::now();
-    let resp = respond_to(context).await;
+    let resp = respond_to(context).instrument(span.clone()).await;
     let end = Instant::now();
     let time_passed = end - start;
     let mut final_resp = match resp {
         Ok(x) => x,
         Err(e) => {
-            let error_id = uuid::Uuid::new_v4();
-            error!(%e, "Error id: {error_id}:");
+            let error_id = &summary.request_id;
+            span.in_scope(|| error!(%e, "Error id: {error_id}:"));
             Response::builder()
                 .status(500)
                 .body(format!("500 Internal Error\n\nError id: {}", error_id).into())?
@@ -146,16 +204,11 @@ async fn wrap_error(context: RequestContext) -> anyhow::Result<Response<Body>> {
         "x-ursa-timings",
         format!("{}ns", time_passed.as_nanos()).try_into()?,
     );
-    return Ok(final_resp);
-}
-
-fn config_var(name: &str) -> anyhow::Result<String> {
-    env::var(format!("URSA_{}", name)).with_context(|| {
-        format!(
-            "Could not find {} expected to be found in the environment at URSA_{}",
-            name, name
-        )
-    })
+    final_resp
+        .headers_mut()
+        .insert("x-request-id", summary.request_id.as_str().try_into()?);
+    access_log::log(&summary, access, final_resp.status().as_u16(), time_passed);
+    Ok(final_resp)
 }
 
 #[allow(non_upper_case_globals)]
@@ -163,51 +216,7 @@ static global_application_config: std::sync::LazyLock<GlobalApplicationContext>
     std::sync::LazyLock::new(|| init_config().unwrap());
 
 fn init_config() -> anyhow::Result<GlobalApplicationContext> {
-    let hypixel_token = config_var("HYPIXEL_TOKEN")?;
-    let allow_anonymous = config_var("ANONYMOUS").unwrap_or("false".to_owned()) == "true";
-    let rules = config_var("RULES")?
-        .split(':')
-        .map(|it| {
-            std::fs::read(it)
-                .map_err(anyhow::Error::from)
-                .and_then(|it| {
-                    serde_json::from_slice::<hypixel::Rule>(&it).map_err(anyhow::Error::from)
-                })
-        })
-        .collect::<Result<Vec<Rule>, _>>()?;
-    let address = IpAddr::from_str(&config_var("ADDRESS").unwrap_or("172.0.0.1".to_owned()))
-        .with_context(|| "Could not parse bind address at URSA_ADDRESS")?;
-    let port = config_var("PORT")?
-        .parse::<u16>()
-        .with_context(|| "Could not parse port at URSA_PORT")?;
-    let token_lifespan = config_var("TOKEN_LIFESPAN")
-        .unwrap_or("3600".to_owned())
-        .parse::<u64>()
-        .with_context(|| "Could not parse token lifespan at URSA_TOKEN_LIFESPAN")?;
-    let secret = config_var("SECRET")?;
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, Body>(https);
-    let redis_url = config_var("REDIS_URL")?;
-    #[cfg(feature = "influxdb")]
-    let influx_url = config_var("INFLUX_URL")?;
-    let rate_limit_lifespan =
-        Duration::from_secs(config_var("RATE_LIMIT_TIMEOUT")?.parse::<u64>()?);
-    let rate_limit_bucket = config_var("RATE_LIMIT_BUCKET")?.parse::<u64>()?;
-    Ok(GlobalApplicationContext {
-        client,
-        address,
-        port,
-        hypixel_token: Obscure(hypixel_token),
-        rules,
-        allow_anonymous,
-        key: Hmac::new_from_slice(secret.as_bytes())?,
-        redis_url: Obscure(redis_url),
-        default_token_duration: Duration::from_secs(token_lifespan),
-        rate_limit_lifespan,
-        rate_limit_bucket,
-        #[cfg(feature = "influxdb")]
-        influx_url,
-    })
+    config::load_config(config::CONFIG_FILE.get().map(PathBuf::as_path))
 }
 
 #[derive(Debug, clap::Subcommand)]
@@ -220,14 +229,47 @@ enum Commands {
         admin: bool,
         #[arg(short, long)]
         name: Option<String>,
+        #[arg(short, long, value_enum)]
+        scope: Vec<mojang::Scope>,
     },
     #[command()]
     Version,
+    /// Check the configuration and report every problem found
+    #[command()]
+    ValidateConfig,
+    /// Decode an NBT file (raw, gzipped or base64 encoded) and print it as json or SNBT
+    #[command()]
+    DecodeNbt {
+        file: PathBuf,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: nbt::NbtFormat,
+    },
+    /// Import NEU inventory reports from a directory of json files into redis
+    #[cfg(feature = "neu")]
+    #[command()]
+    ImportReports {
+        #[arg(default_value = "reports")]
+        directory: PathBuf,
+    },
+    /// Feed auction snapshots recorded via URSA_AH_RECORD_DIR through the price aggregation
+    #[cfg(feature = "lbin")]
+    #[command()]
+    ReplayAuctions {
+        /// Snapshot directories, processed in the given order
+        #[arg(required = true)]
+        snapshots: Vec<PathBuf>,
+        /// Write the resulting prices to influx instead of printing them
+        #[arg(long)]
+        influx: bool,
+    },
 }
 
 #[derive(clap::Parser, Debug)]
 #[command(author = "Linnea Gräf", name = "ursa-minor", version = env!("GIT_HASH"))]
 struct Args {
+    /// A TOML or JSON config file. URSA_ environment variables override its values
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -241,18 +283,55 @@ fn main() -> anyhow::Result<()> {
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn amain() -> anyhow::Result<()> {
     let args = Args::parse();
+    if let Some(config) = args.config {
+        config::CONFIG_FILE.set(config).unwrap();
+    }
     match args.command {
         Commands::Version => {
             println!("{}", meta::debug_string());
         }
+        Commands::ValidateConfig => {
+            match config::load_config(config::CONFIG_FILE.get().map(PathBuf::as_path)) {
+                Ok(config) => println!(
+                    "Configuration is valid, {} rules loaded",
+                    config.rules.len()
+                ),
+                Err(err) => {
+                    eprintln!("{err:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
         Commands::RunServer => run_server().await?,
-        Commands::GenerateToken { admin, name } => {
+        Commands::DecodeNbt { file, format } => {
+            let nbt = nbt::read_nbt_file(&std::fs::read(&file)?)?;
+            println!("{}", format.render(&nbt)?);
+        }
+        #[cfg(feature = "neu")]
+        Commands::ImportReports { directory } => {
+            let Some(mut managed) = global_application_config.storage.connect_redis().await? else {
+                anyhow::bail!("Importing reports requires redis storage");
+            };
+            let count = neu::import_reports(&mut managed, &directory).await?;
+            println!("Imported {count} reports from {}", directory.display());
+        }
+        #[cfg(feature = "lbin")]
+        Commands::ReplayAuctions { snapshots, influx } => {
+            let sink = if influx {
+                lbin::PriceSink::influx()
+            } else {
+                lbin::PriceSink::Stdout
+            };
+            lbin::replay_snapshots(&snapshots, &sink).await?;
+        }
+        Commands::GenerateToken { admin, name, scope } => {
             let principal = mojang::JWTPrincipal {
                 id: mojang::make_null_uuid(),
                 name: name.unwrap_or("generated".to_owned()),
                 valid_until: MillisecondTimestamp(u64::MAX),
                 valid_since: MillisecondTimestamp(0),
                 superuser: admin,
+                scopes: scope,
             };
             println!("Generated token: {}", principal.as_token()?);
         }
@@ -261,13 +340,7 @@ async fn amain() -> anyhow::Result<()> {
 }
 
 async fn run_server() -> anyhow::Result<()> {
-    use tracing_subscriber::layer::SubscriberExt;
-    let terminal_subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .finish()
-        .with(tracing_tracy::TracyLayer::default());
-
-    tracing::subscriber::set_global_default(terminal_subscriber)?;
+    let tracing = telemetry::init()?;
     info!("Ursa minor rises above the sky!");
     info!(
         "Launching with configuration: {:#?}",
@@ -277,36 +350,80 @@ async fn run_server() -> anyhow::Result<()> {
         global_application_config.address,
         global_application_config.port,
     ));
-    let redis_client = redis::Client::open(global_application_config.redis_url.clone())?;
-    let managed = redis::aio::ConnectionManager::new(redis_client).await?;
+    access_log::init()?;
+    let storage = global_application_config.storage.connect()?;
     let service = make_service_fn(|_conn| {
-        let client = managed.clone();
+        let storage = storage.clone();
         async {
             Ok::<_, anyhow::Error>(service_fn(move |req| {
                 wrap_error(RequestContext {
-                    redis_client: Obscure(client.clone()),
+                    storage: storage.clone(),
                     request: req,
+                    access: Default::default(),
                 })
             }))
         }
     });
-    let server = Server::bind(&addr).serve(service);
-    println!("Now listening at {}", addr);
+    let shutdown = Shutdown::default();
     let mut handles = vec![];
-    let shutdown = CancellationToken::new();
-    handles.extend(setup_shutdown_watchers(&shutdown));
+    handles.extend(setup_shutdown_watchers(&shutdown.requested));
+    let deadline = start_shutdown_deadline(&shutdown);
+    #[cfg(feature = "influxdb")]
+    handles.extend(metrics::start_loop(&shutdown));
     #[cfg(feature = "lbin")]
     handles.push(lbin::start_loop(&shutdown));
-    tokio::select! {
-        it = server =>{
-            it?;
+    #[cfg(feature = "bazaar")]
+    handles.push(bazaar::start_loop(&shutdown));
+    let server = Server::bind(&addr).serve(service).with_graceful_shutdown({
+        let requested = shutdown.requested.clone();
+        async move {
+            requested.cancelled().await;
+            meta::set_ready(false);
+            info!(
+                "Reporting as not ready, closing the listener in {:?}",
+                global_application_config.shutdown_grace_period
+            );
+            tokio::time::sleep(global_application_config.shutdown_grace_period).await;
+            info!("Closing the listener, waiting for in-flight requests");
         }
-        _ = shutdown.cancelled() => {}
-    }
+    });
+    println!("Now listening at {}", addr);
+    meta::set_ready(true);
+    let result = tokio::select! {
+        it = server => it.map_err(anyhow::Error::from),
+        _ = shutdown.deadline.cancelled() => {
+            warn!("Shutdown deadline reached, dropping remaining requests");
+            Ok(())
+        }
+    };
+    // Stop the background loops as well if the server failed on its own
+    shutdown.requested.cancel();
     for x in handles {
         x.await?;
     }
-    Ok(())
+    // Everything finished in time, no need to wait for the deadline anymore
+    shutdown.deadline.cancel();
+    deadline.await?;
+    tracing.shutdown();
+    result
+}
+
+/// Cancels the shutdown deadline once the grace period and timeout have passed after shutdown was
+/// requested.
+fn start_shutdown_deadline(shutdown: &Shutdown) -> JoinHandle<()> {
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown.requested.cancelled() => {}
+            _ = shutdown.deadline.cancelled() => return,
+        }
+        let timeout = global_application_config.shutdown_grace_period
+            + global_application_config.shutdown_timeout;
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => shutdown.deadline.cancel(),
+            _ = shutdown.deadline.cancelled() => {}
+        }
+    })
 }
 
 fn setup_shutdown_watchers(token: &CancellationToken) -> [JoinHandle<()>; 2] {
@@ -334,12 +451,17 @@ fn setup_shutdown_watchers(token: &CancellationToken) -> [JoinHandle<()>; 2] {
         {
             let shutdown = token.clone();
             tokio::spawn(async move {
-                if let Err(_) = tokio::signal::ctrl_c().await {
-                    error!(
-                        "Could not set CTRL+C handler. Expect things to get a bit dicey on exit."
-                    );
-                } else {
-                    shutdown.cancel();
+                tokio::select! {
+                    it = tokio::signal::ctrl_c() => {
+                        if it.is_err() {
+                            error!(
+                                "Could not set CTRL+C handler. Expect things to get a bit dicey on exit."
+                            );
+                        } else {
+                            shutdown.cancel();
+                        }
+                    }
+                    _ = shutdown.cancelled() => {}
                 }
             })
         },
@@ -14,33 +14,149 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 use hyper::{Body, Response};
 use serde::Serialize;
 
-use crate::{global_application_config, make_error, require_login, RequestContext};
+use crate::mojang::Scope;
+use crate::{make_error, require_login, RequestContext};
 
 pub const BUILD_VERSION: &str = env!("GIT_HASH");
 
+/// Whether this instance should receive traffic. Set once the server is listening, and cleared as
+/// the first step of shutting down.
+static READY: AtomicBool = AtomicBool::new(false);
+/// When this instance first reported as ready.
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+pub(crate) fn set_ready(ready: bool) {
+    if ready {
+        STARTED.get_or_init(Instant::now);
+    }
+    READY.store(ready, Ordering::SeqCst);
+}
+
+pub(crate) fn is_ready() -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+/// Upper bound for each dependency check, so that a hanging dependency fails the probe instead of
+/// stalling it.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the auction scan may go without succeeding before this instance reports as not ready.
+#[cfg(feature = "lbin")]
+const MAX_SCAN_AGE: Duration = Duration::from_secs(10 * 60);
+/// How long after startup the auction loop may take to report in before it counts as not running.
+#[cfg(feature = "lbin")]
+const SCAN_STARTUP_GRACE: Duration = Duration::from_secs(60);
+
 #[derive(Serialize)]
-struct Stats {
-    request_total: HashMap<String, u64>,
+struct Check {
+    ok: bool,
+    /// Whether a failure of this check makes the instance not ready. Other failures only mark it
+    /// as degraded, as every instance would be affected by them alike.
+    required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
 }
 
-async fn respond_to_statistics(mut req: RequestContext) -> anyhow::Result<Response<Body>> {
-    let mut pipe = redis::pipe();
-    for rule in &global_application_config.rules {
-        pipe.get(rule.accumulated_statistics_key());
+impl Check {
+    fn new(ok: bool, detail: impl Into<Option<String>>) -> Self {
+        Check {
+            ok,
+            required: true,
+            detail: detail.into(),
+        }
     }
-    let response: Vec<Option<u64>> = pipe.query_async(&mut req.redis_client.0).await?;
-    let mut request_total = HashMap::new();
-    for (value, rule) in response.iter().zip(global_application_config.rules.iter()) {
-        request_total.insert(rule.http_path.clone(), value.unwrap_or(0));
+
+    fn optional(self) -> Self {
+        Check {
+            required: false,
+            ..self
+        }
     }
-    return Ok(Response::builder()
+
+    async fn from_future<T, E: std::fmt::Display>(
+        future: impl Future<Output = Result<T, E>>,
+    ) -> Check {
+        match tokio::time::timeout(CHECK_TIMEOUT, future).await {
+            Ok(Ok(_)) => Check::new(true, None),
+            Ok(Err(err)) => Check::new(false, err.to_string()),
+            Err(_) => Check::new(false, format!("Timed out after {CHECK_TIMEOUT:?}")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Whether any optional check failed.
+    degraded: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+async fn respond_to_readiness(req: RequestContext) -> anyhow::Result<Response<Body>> {
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "accepting-traffic",
+        Check::new(
+            is_ready(),
+            (!is_ready()).then(|| "Shutting down".to_owned()),
+        ),
+    );
+    checks.insert(
+        "storage",
+        Check::from_future(req.storage.ping()).await.optional(),
+    );
+    #[cfg(feature = "influxdb")]
+    checks.insert(
+        "influxdb",
+        Check::from_future(
+            influxdb::Client::new(&crate::global_application_config.influx_url, "prices").ping(),
+        )
+        .await,
+    );
+    let circuit = crate::hypixel::circuit_state();
+    checks.insert(
+        "hypixel",
+        Check::new(
+            circuit != crate::hypixel::CircuitState::Open,
+            format!("Circuit is {circuit:?}"),
+        )
+        .optional(),
+    );
+    #[cfg(feature = "lbin")]
+    checks.insert(
+        "auction-scan",
+        match crate::lbin::time_since_last_scan() {
+            None if STARTED
+                .get()
+                .map_or(true, |it| it.elapsed() <= SCAN_STARTUP_GRACE) =>
+            {
+                Check::new(true, "Auction loop is starting".to_owned())
+            }
+            None => Check::new(false, "Auction loop is not running".to_owned()),
+            Some(age) => Check::new(
+                age <= MAX_SCAN_AGE,
+                format!("Last successful scan {}s ago", age.as_secs()),
+            ),
+        },
+    );
+    let readiness = Readiness {
+        ready: checks.values().all(|it| it.ok || !it.required),
+        degraded: checks.values().any(|it| !it.ok && !it.required),
+        checks,
+    };
+    Ok(Response::builder()
+        .status(if readiness.ready { 200 } else { 503 })
         .header("content-type", "application/json")
-        .body(serde_json::to_string(&Stats { request_total })?.into())?);
+        .header("Cache-Control", "no-store")
+        .body(serde_json::to_string(&readiness)?.into())?)
 }
 
 pub async fn respond_to_meta(
@@ -52,13 +168,37 @@ pub async fn respond_to_meta(
             .status(200)
             .body(debug_string().into())?);
     }
+    if meta_path == "health" {
+        return Ok(Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(
+                serde_json::json!({ "status": "alive", "version": BUILD_VERSION })
+                    .to_string()
+                    .into(),
+            )?);
+    }
+    if meta_path == "ready" {
+        return respond_to_readiness(req).await;
+    }
     let (save, principal) = require_login!(req);
     let response = if meta_path == "principal" {
         Response::builder()
             .status(200)
             .body(format!("{principal:#?}").into())?
     } else if meta_path == "stats" {
-        respond_to_statistics(req).await?
+        crate::statistics::respond_to_statistics(req).await?
+    } else if meta_path == "clients" {
+        if !principal.has_scope(Scope::Stats) {
+            return save.save_to(make_error(403, "Missing stats scope")?);
+        }
+        crate::statistics::respond_to_clients(req).await?
+    } else if meta_path == "rules" {
+        Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .body(crate::hypixel::routing_table()?.into())?
     } else {
         make_error(404, format!("Unknown meta request {meta_path}").as_str())?
     };
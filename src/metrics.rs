@@ -0,0 +1,217 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Set once the export is running, so that nothing is collected that never gets written.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Request metrics of this instance since the last export to InfluxDB. Unlike the statistics in
+/// [crate::statistics], these are not shared between instances.
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+#[derive(Default)]
+struct RuleMetrics {
+    statuses: BTreeMap<u16, u64>,
+    latency_total: Duration,
+    latency_max: Duration,
+}
+
+#[derive(Default)]
+struct CacheMetrics {
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    rules: BTreeMap<String, RuleMetrics>,
+    caches: BTreeMap<&'static str, CacheMetrics>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            rules: BTreeMap::new(),
+            caches: BTreeMap::new(),
+        }
+    }
+}
+
+fn with_metrics(action: impl FnOnce(&mut Metrics)) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    action(&mut METRICS.lock().unwrap_or_else(|it| it.into_inner()));
+}
+
+/// Record a proxied request to the rule at `http_path`, with the status it was answered with
+/// and how long it took until the response was ready.
+pub fn record_request(http_path: &str, status: u16, latency: Duration) {
+    with_metrics(|metrics| {
+        let rule = metrics.rules.entry(http_path.to_owned()).or_default();
+        *rule.statuses.entry(status).or_default() += 1;
+        rule.latency_total += latency;
+        rule.latency_max = rule.latency_max.max(latency);
+    });
+}
+
+pub fn record_cache_lookup(cache: &'static str, hit: bool) {
+    with_metrics(|metrics| {
+        let cache = metrics.caches.entry(cache).or_default();
+        if hit {
+            cache.hits += 1;
+        } else {
+            cache.misses += 1;
+        }
+    });
+}
+
+#[cfg(feature = "influxdb")]
+pub(crate) use export::start_loop;
+
+#[cfg(feature = "influxdb")]
+mod export {
+    use std::sync::atomic::Ordering;
+    use std::time::SystemTime;
+
+    use influxdb::InfluxDbWriteable;
+    use tokio::task::JoinHandle;
+    use tracing::{debug, error, info};
+
+    use super::{ENABLED, METRICS};
+    use crate::global_application_config;
+    use crate::util::{MillisecondTimestamp, Shutdown};
+
+    const DATABASE: &str = "ursa";
+
+    #[derive(InfluxDbWriteable)]
+    struct RequestPoint {
+        time: MillisecondTimestamp,
+        requests: u64,
+        rate_limited: u64,
+        latency_mean_ms: f64,
+        latency_max_ms: f64,
+        #[influxdb(tag)]
+        rule: String,
+    }
+
+    #[derive(InfluxDbWriteable)]
+    struct StatusPoint {
+        time: MillisecondTimestamp,
+        requests: u64,
+        #[influxdb(tag)]
+        rule: String,
+        #[influxdb(tag)]
+        status: String,
+    }
+
+    #[derive(InfluxDbWriteable)]
+    struct CachePoint {
+        time: MillisecondTimestamp,
+        hits: u64,
+        misses: u64,
+        hit_ratio: f64,
+        #[influxdb(tag)]
+        cache: String,
+    }
+
+    /// Write everything collected since the last export as one point per rule, status and cache.
+    async fn export() -> anyhow::Result<()> {
+        let metrics = std::mem::take(&mut *METRICS.lock().unwrap_or_else(|it| it.into_inner()));
+        let time = MillisecondTimestamp::from(SystemTime::now());
+        let mut readings = vec![];
+        for (rule, metrics) in metrics.rules {
+            let requests = metrics.statuses.values().sum::<u64>();
+            readings.push(
+                RequestPoint {
+                    time,
+                    requests,
+                    rate_limited: metrics.statuses.get(&429).copied().unwrap_or(0),
+                    latency_mean_ms: metrics.latency_total.as_secs_f64() * 1000.0
+                        / requests.max(1) as f64,
+                    latency_max_ms: metrics.latency_max.as_secs_f64() * 1000.0,
+                    rule: rule.clone(),
+                }
+                .into_query("requests"),
+            );
+            for (status, requests) in metrics.statuses {
+                readings.push(
+                    StatusPoint {
+                        time,
+                        requests,
+                        rule: rule.clone(),
+                        status: status.to_string(),
+                    }
+                    .into_query("request_statuses"),
+                );
+            }
+        }
+        for (cache, metrics) in metrics.caches {
+            readings.push(
+                CachePoint {
+                    time,
+                    hits: metrics.hits,
+                    misses: metrics.misses,
+                    hit_ratio: metrics.hits as f64 / (metrics.hits + metrics.misses).max(1) as f64,
+                    cache: cache.to_owned(),
+                }
+                .into_query("caches"),
+            );
+        }
+        if readings.is_empty() {
+            debug!("No request metrics to export");
+            return Ok(());
+        }
+        let influx = influxdb::Client::new(&global_application_config.influx_url, DATABASE);
+        let res = influx.query(readings).await?;
+        debug!("Request metrics exported to influx: {res}");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn loop_body(shutdown: Shutdown) {
+        info!("Request metrics export started.");
+        let interval = global_application_config.stats_export_interval;
+        loop {
+            let exiting = tokio::select! {
+                _ = shutdown.requested.cancelled() => true,
+                _ = tokio::time::sleep(interval) => false,
+            };
+            if let Err(er) = export().await {
+                error!(%er, "Encountered error during request metrics export");
+            }
+            if exiting {
+                info!("Exiting request metrics export");
+                return;
+            }
+        }
+    }
+
+    /// Start exporting metrics, unless the export is disabled.
+    pub(crate) fn start_loop(shutdown: &Shutdown) -> Option<JoinHandle<()>> {
+        if global_application_config.stats_export_interval.is_zero() {
+            return None;
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        let shutdown = shutdown.clone();
+        Some(tokio::spawn(async move {
+            loop_body(shutdown).await;
+        }))
+    }
+}
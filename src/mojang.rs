@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use anyhow::bail;
 use hyper::body::Buf;
@@ -22,9 +22,12 @@ use hyper::http::HeaderValue;
 use hyper::{Body, Request, Response};
 use jwt::{SignWithKey, VerifyWithKey};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use url::Url;
 use uuid::Uuid;
 
+use crate::access_log::CacheResult;
+use crate::storage::Storage;
 use crate::util::{pure_false, MillisecondTimestamp, UrlForRequest};
 use crate::{global_application_config, make_error, RequestContext};
 
@@ -32,6 +35,16 @@ pub(crate) fn make_null_uuid() -> Uuid {
     Uuid::from_u128(0)
 }
 
+/// Permissions that can be granted to generated tokens. Superusers implicitly have every scope.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Read access to NEU inventory reports
+    Reviewer,
+    /// Read access to client statistics
+    Stats,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct JWTPrincipal {
     pub id: Uuid,
@@ -40,9 +53,15 @@ pub struct JWTPrincipal {
     pub valid_since: MillisecondTimestamp,
     #[serde(default = "pure_false")]
     pub superuser: bool,
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
 }
 
 impl JWTPrincipal {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.superuser || self.scopes.contains(&scope)
+    }
+
     pub fn ratelimit_key(&self) -> String {
         format!("ratelimit:{}", self.id.as_u128())
     }
@@ -61,6 +80,63 @@ pub struct MojangUser {
     pub name: String,
 }
 
+/// How long a resolved player name is cached. Names can only change every 30 days, but a freshly
+/// released name may be claimed by someone else right away.
+const NAME_CACHE_DURATION: Duration = Duration::from_secs(60 * 60);
+/// How long a name that does not belong to any player is cached.
+const UNKNOWN_NAME_CACHE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+pub fn is_valid_player_name(name: &str) -> bool {
+    (1..=16).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Resolve a player name to their uuid using the Mojang API. Lookups, including those for unknown
+/// names, are cached in the storage.
+#[tracing::instrument(skip(storage))]
+pub async fn resolve_player_name(
+    storage: &dyn Storage,
+    name: &str,
+) -> anyhow::Result<(Option<Uuid>, CacheResult)> {
+    let cache_key = format!("mojang:name:{}", name.to_ascii_lowercase());
+    // Without the cache names can still be resolved, just more slowly
+    let cached = storage.get(&cache_key).await.unwrap_or_else(|err| {
+        warn!(%err, "Could not read the name cache, treating it as a miss");
+        None
+    });
+    crate::metrics::record_cache_lookup("mojang-name", cached.is_some());
+    if let Some(cached) = cached {
+        // Unknown names are cached as an empty string
+        return Ok((Uuid::parse_str(&cached).ok(), CacheResult::Hit));
+    }
+    let mojang_request = Request::builder()
+        .url(Url::parse("https://api.mojang.com/users/profiles/minecraft/")?.join(name)?)?
+        .body(Body::empty())?;
+    let mojang_response = global_application_config
+        .client
+        .request(mojang_request)
+        .await?;
+    let (uuid, lifespan) = match mojang_response.status().as_u16() {
+        200 => {
+            let buffer = hyper::body::aggregate(mojang_response).await?;
+            let user = serde_json::from_reader::<_, MojangUser>(buffer.reader())?;
+            (Some(user.id), NAME_CACHE_DURATION)
+        }
+        204 | 404 => (None, UNKNOWN_NAME_CACHE_DURATION),
+        status => bail!("Mojang profile lookup failed with status {status}"),
+    };
+    if let Err(err) = storage
+        .set_expiring(
+            &cache_key,
+            &uuid.map(|it| it.simple().to_string()).unwrap_or_default(),
+            lifespan,
+        )
+        .await
+    {
+        warn!(%err, "Could not cache resolved player name");
+    }
+    Ok((uuid, CacheResult::Miss))
+}
+
 #[must_use]
 pub enum SaveOnExit {
     DontSave,
@@ -97,8 +173,19 @@ macro_rules! require_login {
         }
     };
 }
+#[tracing::instrument(skip_all)]
 pub async fn require_login(
     req: &RequestContext,
+) -> anyhow::Result<Result<(SaveOnExit, JWTPrincipal), Response<Body>>> {
+    let login = authenticate(req).await?;
+    if let Ok((_, principal)) = &login {
+        req.access.set_principal(principal.id);
+    }
+    Ok(login)
+}
+
+async fn authenticate(
+    req: &RequestContext,
 ) -> anyhow::Result<Result<(SaveOnExit, JWTPrincipal), Response<Body>>> {
     if global_application_config.allow_anonymous {
         return Ok(Ok((
@@ -109,6 +196,7 @@ pub async fn require_login(
                 valid_until: MillisecondTimestamp(u64::MAX),
                 valid_since: MillisecondTimestamp(0),
                 superuser: false,
+                scopes: vec![],
             },
         )));
     }
@@ -151,7 +239,7 @@ async fn verify_existing_login(req: &RequestContext) -> anyhow::Result<Option<JW
     };
     let claims: JWTPrincipal =
         VerifyWithKey::verify_with_key(token, &global_application_config.key)?;
-    let right_now = MillisecondTimestamp::try_from(SystemTime::now())?;
+    let right_now = MillisecondTimestamp::from(SystemTime::now());
     if claims.valid_since > right_now || claims.valid_until < right_now {
         bail!("JWT not valid");
     }
@@ -195,12 +283,13 @@ async fn verify_login_attempt(
     }
     let buffer = hyper::body::aggregate(mojang_response).await?;
     let user = serde_json::from_reader::<_, MojangUser>(buffer.reader())?;
-    let right_now = MillisecondTimestamp::try_from(SystemTime::now())?;
+    let right_now = MillisecondTimestamp::from(SystemTime::now());
     Ok(Ok(JWTPrincipal {
         id: user.id,
         name: user.name,
         valid_until: right_now + global_application_config.default_token_duration,
         valid_since: right_now,
         superuser: false,
+        scopes: vec![],
     }))
 }
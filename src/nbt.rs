@@ -0,0 +1,360 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Write as _;
+use std::io::{Cursor, Read};
+
+use base64::Engine;
+use hyper::{Body, Method, Response};
+use serde_json::Value;
+use simdnbt::owned::{BaseNbt, Nbt, NbtCompound, NbtList, NbtTag};
+use simdnbt::Mutf8Str;
+
+use crate::util::read_body_limited;
+use crate::{make_error, RequestContext};
+
+/// Largest base64 payload accepted by the decode endpoint.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+/// Upper bound for decompressed NBT, so a small gzip bomb cannot exhaust memory.
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const COMPOUND_ID: u8 = 0x0a;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NbtFormat {
+    #[default]
+    Json,
+    Snbt,
+}
+
+impl NbtFormat {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(NbtFormat::Json),
+            "snbt" => Some(NbtFormat::Snbt),
+            _ => None,
+        }
+    }
+
+    pub fn render(self, nbt: &BaseNbt) -> anyhow::Result<String> {
+        Ok(match self {
+            NbtFormat::Json => serde_json::to_string(&compound_to_json(nbt))?,
+            NbtFormat::Snbt => to_snbt(nbt),
+        })
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            NbtFormat::Json => "application/json",
+            NbtFormat::Snbt => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// Read a root compound from either gzip compressed or raw NBT.
+pub fn read_nbt(data: &[u8]) -> anyhow::Result<BaseNbt> {
+    let mut ungzipped = Vec::new();
+    let raw = if data.starts_with(&GZIP_MAGIC) {
+        flate2::read::GzDecoder::new(data)
+            .take(MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut ungzipped)?;
+        if ungzipped.len() as u64 > MAX_DECOMPRESSED_SIZE {
+            anyhow::bail!("Decompressed NBT exceeds {MAX_DECOMPRESSED_SIZE} bytes");
+        }
+        ungzipped.as_slice()
+    } else {
+        data
+    };
+    let mut c: Cursor<&[u8]> = Cursor::new(raw);
+    match simdnbt::owned::read(&mut c)? {
+        Nbt::Some(nbt) => Ok(nbt),
+        Nbt::None => anyhow::bail!("Empty NBT"),
+    }
+}
+
+/// Read NBT from a file, which may contain raw NBT, gzipped NBT or base64 encoded gzipped NBT.
+pub fn read_nbt_file(data: &[u8]) -> anyhow::Result<BaseNbt> {
+    if data.starts_with(&GZIP_MAGIC) || data.first() == Some(&COMPOUND_ID) {
+        return read_nbt(data);
+    }
+    let text = std::str::from_utf8(data)?.trim();
+    read_nbt(&base64::engine::general_purpose::STANDARD.decode(text)?)
+}
+
+fn string_to_json(str: &Mutf8Str) -> Value {
+    Value::String(str.to_string_lossy().into_owned())
+}
+
+fn float_to_json(value: f64) -> Value {
+    serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+pub fn compound_to_json(compound: &NbtCompound) -> Value {
+    Value::Object(
+        compound
+            .iter()
+            .map(|(name, tag)| (name.to_string_lossy().into_owned(), tag_to_json(tag)))
+            .collect(),
+    )
+}
+
+fn list_to_json(list: &NbtList) -> Value {
+    Value::Array(list.as_nbt_tags().iter().map(tag_to_json).collect())
+}
+
+pub fn tag_to_json(tag: &NbtTag) -> Value {
+    match tag {
+        NbtTag::Byte(it) => Value::from(*it),
+        NbtTag::Short(it) => Value::from(*it),
+        NbtTag::Int(it) => Value::from(*it),
+        NbtTag::Long(it) => Value::from(*it),
+        NbtTag::Float(it) => float_to_json(*it as f64),
+        NbtTag::Double(it) => float_to_json(*it),
+        NbtTag::ByteArray(it) => Value::from(it.iter().map(|b| *b as i8).collect::<Vec<_>>()),
+        NbtTag::String(it) => string_to_json(it),
+        NbtTag::List(it) => list_to_json(it),
+        NbtTag::Compound(it) => compound_to_json(it),
+        NbtTag::IntArray(it) => Value::from(it.clone()),
+        NbtTag::LongArray(it) => Value::from(it.clone()),
+    }
+}
+
+/// Render a compound as stringified NBT, the format used by Minecraft commands.
+pub fn to_snbt(compound: &NbtCompound) -> String {
+    let mut out = String::new();
+    write_compound(&mut out, compound);
+    out
+}
+
+fn write_string(out: &mut String, str: &str) {
+    out.push('"');
+    for c in str.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+fn write_key(out: &mut String, key: &str) {
+    let is_bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '+'));
+    if is_bare {
+        out.push_str(key);
+    } else {
+        write_string(out, key);
+    }
+}
+
+fn write_array<T: std::fmt::Display>(out: &mut String, prefix: char, suffix: &str, values: &[T]) {
+    let _ = write!(out, "[{prefix};");
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{value}{suffix}");
+    }
+    out.push(']');
+}
+
+fn write_compound(out: &mut String, compound: &NbtCompound) {
+    out.push('{');
+    for (i, (name, tag)) in compound.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_key(out, &name.to_string_lossy());
+        out.push(':');
+        write_tag(out, tag);
+    }
+    out.push('}');
+}
+
+fn write_tag(out: &mut String, tag: &NbtTag) {
+    let _ = match tag {
+        NbtTag::Byte(it) => write!(out, "{it}b"),
+        NbtTag::Short(it) => write!(out, "{it}s"),
+        NbtTag::Int(it) => write!(out, "{it}"),
+        NbtTag::Long(it) => write!(out, "{it}L"),
+        NbtTag::Float(it) => write!(out, "{it}f"),
+        NbtTag::Double(it) => write!(out, "{it}d"),
+        NbtTag::ByteArray(it) => {
+            let signed: Vec<i8> = it.iter().map(|b| *b as i8).collect();
+            write_array(out, 'B', "b", &signed);
+            Ok(())
+        }
+        NbtTag::String(it) => {
+            write_string(out, &it.to_string_lossy());
+            Ok(())
+        }
+        NbtTag::List(it) => {
+            out.push('[');
+            for (i, tag) in it.as_nbt_tags().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_tag(out, tag);
+            }
+            out.push(']');
+            Ok(())
+        }
+        NbtTag::Compound(it) => {
+            write_compound(out, it);
+            Ok(())
+        }
+        NbtTag::IntArray(it) => {
+            write_array(out, 'I', "", it);
+            Ok(())
+        }
+        NbtTag::LongArray(it) => {
+            write_array(out, 'L', "L", it);
+            Ok(())
+        }
+    };
+}
+
+pub async fn respond_to(
+    context: &mut RequestContext,
+    path: &str,
+) -> anyhow::Result<Option<Response<Body>>> {
+    if path != "decode" {
+        return Ok(None);
+    }
+    let request = &mut context.request;
+    if request.method() != Method::POST {
+        return make_error(405, "Use POST with a base64 encoded NBT body").map(Some);
+    }
+    let mut format = NbtFormat::default();
+    if let Some(query) = request.uri().query() {
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match (key.as_ref(), NbtFormat::parse(&value)) {
+                ("format", Some(it)) => format = it,
+                ("format", None) => {
+                    return make_error(400, &format!("Unknown format {value}")).map(Some)
+                }
+                _ => return make_error(400, &format!("Unknown query parameter {key}")).map(Some),
+            }
+        }
+    }
+    let Some(body) =
+        read_body_limited(std::mem::take(request.body_mut()), MAX_REQUEST_SIZE).await?
+    else {
+        return make_error(413, "Request too large").map(Some);
+    };
+    // Decompressing and rendering large NBT takes a while, so keep it off the async workers
+    let rendered =
+        tokio::task::spawn_blocking(move || -> anyhow::Result<Result<String, String>> {
+            let decoded = std::str::from_utf8(&body)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| Ok(base64::engine::general_purpose::STANDARD.decode(text.trim())?))
+                .and_then(|bytes| read_nbt(&bytes));
+            match decoded {
+                Ok(nbt) => Ok(Ok(format.render(&nbt)?)),
+                Err(err) => Ok(Err(format!("Could not decode NBT: {err}"))),
+            }
+        })
+        .await??;
+    let rendered = match rendered {
+        Ok(it) => it,
+        Err(err) => return make_error(400, &err).map(Some),
+    };
+    Ok(Some(
+        Response::builder()
+            .status(200)
+            .header("content-type", format.content_type())
+            .body(rendered.into())?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compound(values: Vec<(&str, NbtTag)>) -> NbtCompound {
+        NbtCompound::from_values(
+            values
+                .into_iter()
+                .map(|(name, tag)| (name.into(), tag))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn writes_number_suffixes() {
+        let nbt = compound(vec![
+            ("byte", NbtTag::Byte(-1)),
+            ("short", NbtTag::Short(2)),
+            ("int", NbtTag::Int(3)),
+            ("long", NbtTag::Long(4)),
+            ("float", NbtTag::Float(0.5)),
+            ("double", NbtTag::Double(1.5)),
+        ]);
+        assert_eq!(
+            to_snbt(&nbt),
+            "{byte:-1b,short:2s,int:3,long:4L,float:0.5f,double:1.5d}"
+        );
+    }
+
+    #[test]
+    fn writes_array_suffixes() {
+        let nbt = compound(vec![
+            ("bytes", NbtTag::ByteArray(vec![1, 255])),
+            ("ints", NbtTag::IntArray(vec![1, -2])),
+            ("longs", NbtTag::LongArray(vec![3, 4])),
+            ("empty", NbtTag::IntArray(vec![])),
+        ]);
+        assert_eq!(
+            to_snbt(&nbt),
+            "{bytes:[B;1b,-1b],ints:[I;1,-2],longs:[L;3L,4L],empty:[I;]}"
+        );
+    }
+
+    #[test]
+    fn escapes_strings_and_keys() {
+        let nbt = compound(vec![
+            ("plain_key.1", NbtTag::String("say \"hi\"".into())),
+            ("with space", NbtTag::String("back\\slash".into())),
+            ("", NbtTag::String("§6Gold".into())),
+        ]);
+        assert_eq!(
+            to_snbt(&nbt),
+            r#"{plain_key.1:"say \"hi\"","with space":"back\\slash","":"§6Gold"}"#
+        );
+    }
+
+    #[test]
+    fn writes_nested_lists_and_compounds() {
+        let nbt = compound(vec![
+            (
+                "display",
+                NbtTag::Compound(compound(vec![(
+                    "Lore",
+                    NbtTag::List(vec!["a".to_owned(), "b".to_owned()].into()),
+                )])),
+            ),
+            ("counts", NbtTag::List(vec![1i16, 2].into())),
+            ("none", NbtTag::List(NbtList::Empty)),
+        ]);
+        assert_eq!(
+            to_snbt(&nbt),
+            r#"{display:{Lore:["a","b"]},counts:[1s,2s],none:[]}"#
+        );
+    }
+}
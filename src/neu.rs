@@ -1,52 +1,544 @@
-use hyper::{body::Buf, Body, Response};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response};
+use redis::aio::ConnectionManager;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::item::{decode_item_stack, ItemStack};
+use crate::mojang::{JWTPrincipal, Scope};
+use crate::storage::Storage;
+use crate::util::{read_body_limited, MillisecondTimestamp, Obscure};
+use crate::{make_error, RequestContext};
 
-use crate::{mojang::JWTPrincipal, util::MillisecondTimestamp, RequestContext};
+/// Sorted set of all report ids, scored by their timestamp.
+const REPORTS_BY_TIME: &str = "neu:reports:by-time";
+
+fn report_key(report_uuid: impl Display) -> String {
+    format!("neu:report:{report_uuid}")
+}
+
+/// Sorted set of the report ids of one reporter, scored by their timestamp.
+fn reporter_index_key(reporter_uuid: impl Display) -> String {
+    format!("neu:reports:by-reporter:{reporter_uuid}")
+}
+
+/// Sorted set of the ids of reports containing an item with the given SkyBlock id, scored by
+/// their timestamp.
+fn item_index_key(skyblock_id: &str) -> String {
+    format!("neu:reports:by-item:{skyblock_id}")
+}
+
+/// Sorted set of the report ids for one inventory title, scored by their timestamp.
+fn title_index_key(title: &str) -> String {
+    format!("neu:reports:by-title:{title}")
+}
+
+/// Set of the uuids of everyone who submitted the same report.
+fn reporters_key(report_uuid: impl Display) -> String {
+    format!("neu:report:{report_uuid}:reporters")
+}
+
+/// The id of the report with the given [Inventory::content_hash].
+fn content_hash_key(hash: &str) -> String {
+    format!("neu:reports:by-hash:{hash}")
+}
+
+/// The [ReportStatus] of a report, absent for open reports.
+fn status_key(report_uuid: impl Display) -> String {
+    format!("neu:report:{report_uuid}:status")
+}
+
+/// List of the [Note]s attached to a report, oldest first.
+fn notes_key(report_uuid: impl Display) -> String {
+    format!("neu:report:{report_uuid}:notes")
+}
+
+/// List of all [ModerationEvent]s, newest first.
+const MODERATION_CHANGELOG: &str = "neu:moderation:changelog";
+
+/// List of the [ModerationEvent]s caused by one principal, newest first.
+fn actor_changelog_key(actor: Uuid) -> String {
+    format!("neu:moderation:changelog:{actor}")
+}
+
+fn report_rate_limit_key(reporter_uuid: Uuid) -> String {
+    format!("ratelimit:neu-report:{}", reporter_uuid.as_u128())
+}
+
+async fn store_report(redis: &mut ConnectionManager, report: &Report) -> anyhow::Result<()> {
+    let report_id = report.report_uuid.to_string();
+    let score = report.report_timestamp.0;
+    let mut pipe = redis::pipe();
+    for skyblock_id in report.inventory.skyblock_ids() {
+        pipe.zadd(item_index_key(skyblock_id), &report_id, score)
+            .ignore();
+    }
+    pipe.atomic()
+        .set(report_key(report.report_uuid), serde_json::to_vec(report)?)
+        .ignore()
+        .set(
+            content_hash_key(&report.inventory.content_hash()?),
+            &report_id,
+        )
+        .ignore()
+        .sadd(reporters_key(&report_id), report.reporter_uuid.to_string())
+        .ignore()
+        .zadd(REPORTS_BY_TIME, &report_id, score)
+        .ignore()
+        .zadd(reporter_index_key(report.reporter_uuid), &report_id, score)
+        .ignore()
+        .zadd(title_index_key(&report.inventory.title), &report_id, score)
+        .ignore()
+        .query_async::<_, ()>(redis)
+        .await?;
+    Ok(())
+}
+
+/// The report json, reporter count, status json and note jsons of one report.
+type StoredReport = (Option<Vec<u8>>, u64, Option<String>, Vec<Vec<u8>>);
+
+/// Load the given reports in order, skipping ids whose report no longer exists.
+async fn load_reports(
+    redis: &mut ConnectionManager,
+    report_ids: &[String],
+) -> anyhow::Result<Vec<Report>> {
+    if report_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipe = redis::pipe();
+    for report_id in report_ids {
+        pipe.get(report_key(report_id))
+            .scard(reporters_key(report_id))
+            .get(status_key(report_id))
+            .lrange(notes_key(report_id), 0, -1);
+    }
+    let raw: Vec<StoredReport> = pipe.query_async(redis).await?;
+    raw.into_iter()
+        .filter_map(|(report, reporter_count, status, notes)| {
+            Some((report?, reporter_count, status, notes))
+        })
+        .map(|(report, reporter_count, status, notes)| {
+            let mut report = serde_json::from_slice::<Report>(&report)?;
+            report.reporter_count = reporter_count;
+            report.status = match status {
+                Some(status) => serde_json::from_str(&status)?,
+                None => ReportStatus::Open,
+            };
+            report.notes = notes
+                .iter()
+                .map(|it| serde_json::from_slice(it))
+                .collect::<Result<_, _>>()?;
+            Ok(report)
+        })
+        .collect()
+}
+
+/// Record another reporter for an already stored report. Returns false if that report no
+/// longer exists or was already closed, in which case the inventory needs a new report.
+async fn add_reporter(
+    redis: &mut ConnectionManager,
+    report_id: &str,
+    reporter_uuid: Uuid,
+) -> anyhow::Result<bool> {
+    let (score, status): (Option<u64>, Option<String>) = redis::pipe()
+        .zscore(REPORTS_BY_TIME, report_id)
+        .get(status_key(report_id))
+        .query_async(redis)
+        .await?;
+    let Some(score) = score else {
+        return Ok(false);
+    };
+    if let Some(status) = status {
+        if serde_json::from_str::<ReportStatus>(&status)?.is_closed() {
+            return Ok(false);
+        }
+    }
+    redis::pipe()
+        .atomic()
+        .sadd(reporters_key(report_id), reporter_uuid.to_string())
+        .ignore()
+        .zadd(reporter_index_key(reporter_uuid), report_id, score)
+        .ignore()
+        .query_async::<_, ()>(redis)
+        .await?;
+    Ok(true)
+}
+
+async fn import_report(redis: &mut ConnectionManager, path: &Path) -> anyhow::Result<()> {
+    let data = tokio::fs::read(path).await?;
+    let mut report = serde_json::from_slice::<Report>(&data)?;
+    for slot in &mut report.inventory.slots {
+        if let Some(item) = &slot.item {
+            slot.decoded = DecodedItem::decode(item).ok();
+        }
+    }
+    store_report(redis, &report).await
+}
+
+/// Import reports from the json files previously written to the given directory. Files that
+/// fail to import are skipped and reported together once every other file is imported.
+/// Returns the amount of imported reports. Importing the same directory twice is harmless.
+pub async fn import_reports(
+    redis: &mut ConnectionManager,
+    directory: &Path,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    let mut failures = vec![];
+    let mut files = tokio::fs::read_dir(directory).await?;
+    while let Some(file) = files.next_entry().await? {
+        let path = file.path();
+        if path.extension().map_or(true, |it| it != "json") {
+            continue;
+        }
+        match import_report(redis, &path).await {
+            Ok(()) => count += 1,
+            Err(err) => failures.push(format!("{}: {err}", path.display())),
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "Imported {count} reports, but {} could not be imported:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+    Ok(count)
+}
+
+/// Inventory reports need to be persistent, so they are always stored in redis directly instead of
+/// going through the storage abstraction.
+struct NeuContext {
+    storage: Arc<dyn Storage>,
+    redis_client: Obscure<ConnectionManager, "ConnectionManager">,
+    request: Request<Body>,
+}
 
 pub async fn respond_to(
     context: RequestContext,
     path: &str,
     principal: JWTPrincipal,
 ) -> anyhow::Result<Option<Response<Body>>> {
+    let redis_client = match context.storage.redis() {
+        Some(Ok(it)) => it,
+        Some(Err(err)) => {
+            warn!(%err, "Could not access inventory reports");
+            return make_error(503, "Inventory reports are currently unavailable").map(Some);
+        }
+        None => return make_error(503, "Inventory reports require redis storage").map(Some),
+    };
+    let context = NeuContext {
+        storage: context.storage,
+        redis_client: Obscure(redis_client),
+        request: context.request,
+    };
     if path == "reportinventory" {
-        return Ok(report_inventory(context, &principal).await.map(Some)?);
+        return report_inventory(context, &principal).await.map(Some);
     }
     if path == "requestinventories" {
-        return Ok(request_inventory().await.map(Some)?);
+        if !principal.has_scope(Scope::Reviewer) {
+            return make_error(403, "Missing reviewer scope").map(Some);
+        }
+        return request_inventory(context).await.map(Some);
+    }
+    if path == "moderation/changelog" {
+        if !principal.superuser {
+            return make_error(403, "Moderation requires a superuser").map(Some);
+        }
+        return request_changelog(context).await.map(Some);
+    }
+    if let Some(report_path) = path.strip_prefix("inventories/") {
+        let (report_uuid, action) = report_path.split_once('/').unwrap_or((report_path, ""));
+        let Ok(report_uuid) = Uuid::parse_str(report_uuid) else {
+            return make_error(400, "Invalid report uuid").map(Some);
+        };
+        let method = context.request.method().clone();
+        if method == Method::GET && action.is_empty() {
+            if !principal.has_scope(Scope::Reviewer) {
+                return make_error(403, "Missing reviewer scope").map(Some);
+            }
+            return fetch_inventory(context, report_uuid).await.map(Some);
+        }
+        if !principal.superuser {
+            return make_error(403, "Moderation requires a superuser").map(Some);
+        }
+        return match (method, action) {
+            (Method::DELETE, "") => delete_inventory(context, report_uuid, &principal).await,
+            (Method::POST, "status") => set_status(context, report_uuid, &principal).await,
+            (Method::POST, "notes") => add_note(context, report_uuid, &principal).await,
+            _ => make_error(405, "Unsupported report action"),
+        }
+        .map(Some);
     }
     Ok(None)
 }
 
-async fn request_inventory() -> anyhow::Result<Response<Body>> {
-    let mut content = vec![];
-    let mut d = vec![];
-    if tokio::fs::try_exists("reports").await.unwrap_or(false) {
-        let mut files = tokio::fs::read_dir("reports").await?;
-        while let Some(file) = files.next_entry().await? {
-            let mut file = tokio::fs::File::open(file.path()).await?;
-            file.read_to_end(&mut d).await?;
-            let data = serde_json::from_slice::<Report>(&d)?;
-            content.push(data);
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+/// How many report ids are read from an index at once while filtering.
+const INDEX_BATCH_SIZE: isize = 200;
+
+/// Position after the last returned report. Reports are ordered newest first, with reports that
+/// have the same timestamp ordered by their id.
+#[derive(Clone, Copy)]
+struct Cursor {
+    timestamp: u64,
+    report_uuid: Uuid,
+}
+
+impl Cursor {
+    fn parse(text: &str) -> Option<Self> {
+        let (timestamp, report_uuid) = text.split_once(':')?;
+        Some(Cursor {
+            timestamp: timestamp.parse().ok()?,
+            report_uuid: Uuid::parse_str(report_uuid).ok()?,
+        })
+    }
+
+    fn of(report: &Report) -> Self {
+        Cursor {
+            timestamp: report.report_timestamp.0,
+            report_uuid: report.report_uuid,
         }
     }
 
-    return Ok(Response::builder()
+    /// Whether an index entry comes after this cursor.
+    fn is_before(&self, timestamp: u64, report_id: &str) -> bool {
+        timestamp < self.timestamp
+            || (timestamp == self.timestamp && *report_id < *self.report_uuid.to_string())
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.timestamp, self.report_uuid)
+    }
+}
+
+#[derive(Default)]
+struct ReportQuery {
+    cursor: Option<Cursor>,
+    limit: usize,
+    title: Option<String>,
+    exact_title: Option<String>,
+    reporter: Option<Uuid>,
+    item: Option<String>,
+    status: Option<ReportStatus>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+impl ReportQuery {
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut result = ReportQuery {
+            limit: DEFAULT_PAGE_SIZE,
+            ..Default::default()
+        };
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "cursor" => {
+                    result.cursor =
+                        Some(Cursor::parse(&value).ok_or_else(|| "Invalid cursor".to_owned())?)
+                }
+                "limit" => {
+                    result.limit = value
+                        .parse::<usize>()
+                        .map_err(|_| "Invalid limit".to_owned())?
+                        .clamp(1, MAX_PAGE_SIZE)
+                }
+                "title" => result.title = Some(value.to_lowercase()),
+                "exact_title" => result.exact_title = Some(value.into_owned()),
+                "reporter" => {
+                    result.reporter = Some(
+                        Uuid::parse_str(&value).map_err(|_| "Invalid reporter uuid".to_owned())?,
+                    )
+                }
+                "item" => result.item = Some(value.into_owned()),
+                "status" => {
+                    result.status = Some(
+                        serde_json::from_value(serde_json::Value::String(value.into_owned()))
+                            .map_err(|_| "Invalid status".to_owned())?,
+                    )
+                }
+                "from" => result.from = Some(value.parse().map_err(|_| "Invalid from".to_owned())?),
+                "to" => result.to = Some(value.parse().map_err(|_| "Invalid to".to_owned())?),
+                _ => return Err(format!("Unknown query parameter {key}")),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Filters that can not be answered by the index the reports are read from.
+    fn matches(&self, report: &Report) -> bool {
+        let title_matches = self.title.as_ref().map_or(true, |title| {
+            report.inventory.title.to_lowercase().contains(title)
+        });
+        let exact_title_matches = self
+            .exact_title
+            .as_ref()
+            .map_or(true, |title| report.inventory.title == *title);
+        let item_matches = self.item.as_ref().map_or(true, |item| {
+            report.inventory.skyblock_ids().contains(item.as_str())
+        });
+        let status_matches = self.status.map_or(true, |it| it == report.status);
+        title_matches && exact_title_matches && item_matches && status_matches
+    }
+}
+
+async fn request_inventory(mut context: NeuContext) -> anyhow::Result<Response<Body>> {
+    let query = match ReportQuery::parse(context.request.uri().query().unwrap_or("")) {
+        Ok(query) => query,
+        Err(error) => return make_error(400, &error),
+    };
+    let index = match (query.reporter, &query.exact_title, &query.item) {
+        (Some(reporter), _, _) => reporter_index_key(reporter),
+        (None, Some(title), _) => title_index_key(title),
+        (None, None, Some(item)) => item_index_key(item),
+        (None, None, None) => REPORTS_BY_TIME.to_owned(),
+    };
+    let min = query.from.map_or("-inf".to_owned(), |it| it.to_string());
+    let mut entries = vec![];
+    // Read the index in batches starting after the last entry seen so far, so that reports
+    // deleted in the meantime can not shift later reports out of view
+    let mut position = query.cursor;
+    let mut batch_size = INDEX_BATCH_SIZE;
+    let mut exhausted = false;
+    while entries.len() < query.limit {
+        let max = match (position, query.to) {
+            (Some(position), Some(to)) => position.timestamp.min(to).to_string(),
+            (Some(position), None) => position.timestamp.to_string(),
+            (None, Some(to)) => to.to_string(),
+            (None, None) => "+inf".to_owned(),
+        };
+        let batch: Vec<(String, u64)> =
+            redis::Cmd::zrevrangebyscore_limit_withscores(&index, &max, &min, 0, batch_size)
+                .query_async(&mut context.redis_client.0)
+                .await?;
+        let report_ids = batch
+            .iter()
+            .filter(|(id, timestamp)| position.map_or(true, |it| it.is_before(*timestamp, id)))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        let full = batch.len() as isize == batch_size;
+        if report_ids.is_empty() && full {
+            // The whole batch shares the timestamp of the position, read more of it at once
+            batch_size *= 2;
+            continue;
+        }
+        if let Some((id, timestamp)) = batch.last() {
+            position = Some(Cursor {
+                timestamp: *timestamp,
+                report_uuid: Uuid::parse_str(id)?,
+            });
+        }
+        let reports = load_reports(&mut context.redis_client, &report_ids).await?;
+        entries.extend(reports.into_iter().filter(|it| query.matches(it)));
+        if !full {
+            exhausted = true;
+            break;
+        }
+    }
+    let truncated = entries.len() > query.limit;
+    entries.truncate(query.limit);
+    let next_cursor = if exhausted && !truncated {
+        None
+    } else {
+        entries.last().map(|it| Cursor::of(it).to_string())
+    };
+    Ok(Response::builder()
         .status(200)
         .header("content-type", "application/json")
-        .body(serde_json::to_string(&InventoryList { entries: content })?.into())?
-        .into());
+        .body(
+            serde_json::to_string(&InventoryList {
+                entries,
+                next_cursor,
+            })?
+            .into(),
+        )?)
+}
+
+async fn fetch_inventory(
+    mut context: NeuContext,
+    report_uuid: Uuid,
+) -> anyhow::Result<Response<Body>> {
+    let Some(report) = load_reports(&mut context.redis_client, &[report_uuid.to_string()])
+        .await?
+        .pop()
+    else {
+        return make_error(404, "Unknown report");
+    };
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&report)?.into())?)
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct InventoryList {
     entries: Vec<Report>,
+    /// Pass as `cursor` to fetch the next page. Absent if there are no more reports.
+    next_cursor: Option<String>,
 }
 
+/// Largest accepted report body in bytes.
+const MAX_REPORT_SIZE: usize = 256 * 1024;
+/// Largest accepted slot count and exclusive upper bound of slot indices. A double chest and the
+/// player inventory together take up 90 slots.
+const MAX_SLOTS: usize = 128;
+const MAX_TITLE_LENGTH: usize = 256;
+/// How many reports one player may submit per [REPORT_RATE_LIMIT_WINDOW].
+const REPORT_RATE_LIMIT: u64 = 30;
+const REPORT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(3600);
+
 #[derive(Deserialize, Serialize)]
 pub struct Slot {
     slot_index: i32,
     item: Option<String>,
+    /// Filled in from [Slot::item] when the report is received.
+    #[serde(default)]
+    decoded: Option<DecodedItem>,
+}
+
+/// The parts of an item that reviewers care about, decoded from its NBT.
+#[derive(Deserialize, Serialize)]
+pub struct DecodedItem {
+    skyblock_id: Option<String>,
+    display_name: Option<String>,
+    #[serde(default)]
+    lore: Vec<String>,
+    #[serde(default)]
+    enchantments: BTreeMap<String, i32>,
+    uuid: Option<String>,
+}
+
+impl DecodedItem {
+    fn decode(item: &str) -> anyhow::Result<Self> {
+        let compound = decode_item_stack(item)?;
+        let stack = ItemStack::new(&compound);
+        if !stack.is_item() {
+            anyhow::bail!("Item stack is missing its id or tag");
+        }
+        let extra_attributes = stack.extra_attributes();
+        Ok(DecodedItem {
+            skyblock_id: extra_attributes
+                .as_ref()
+                .and_then(|it| it.id())
+                .map(|it| it.to_string()),
+            display_name: stack.display_name(),
+            lore: stack.lore().unwrap_or_default(),
+            enchantments: extra_attributes
+                .as_ref()
+                .and_then(|it| it.enchantments())
+                .unwrap_or_default(),
+            uuid: extra_attributes.as_ref().and_then(|it| it.uuid()),
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -55,33 +547,495 @@ pub struct Inventory {
     slots: Vec<Slot>,
 }
 
+impl Inventory {
+    /// Check the bounds of this inventory and decode all items, rejecting undecodable ones.
+    fn validate(&mut self) -> Result<(), String> {
+        if self.title.len() > MAX_TITLE_LENGTH {
+            return Err("Inventory title too long".to_owned());
+        }
+        if self.slots.len() > MAX_SLOTS {
+            return Err(format!("Too many slots, at most {MAX_SLOTS} are allowed"));
+        }
+        let mut seen = HashSet::new();
+        for slot in &mut self.slots {
+            if !(0..MAX_SLOTS as i32).contains(&slot.slot_index) {
+                return Err(format!("Slot index {} out of range", slot.slot_index));
+            }
+            if !seen.insert(slot.slot_index) {
+                return Err(format!("Duplicate slot index {}", slot.slot_index));
+            }
+            if let Some(item) = &slot.item {
+                slot.decoded =
+                    Some(DecodedItem::decode(item).map_err(|err| {
+                        format!("Invalid item in slot {}: {err}", slot.slot_index)
+                    })?);
+            }
+        }
+        Ok(())
+    }
+
+    fn skyblock_ids(&self) -> HashSet<&str> {
+        self.slots
+            .iter()
+            .filter_map(|it| it.decoded.as_ref()?.skyblock_id.as_deref())
+            .collect()
+    }
+
+    /// Hex encoded sha256 over the title and the slots ordered by index, used to recognize
+    /// identical reports.
+    fn content_hash(&self) -> anyhow::Result<String> {
+        let mut slots = self
+            .slots
+            .iter()
+            .map(|it| (it.slot_index, &it.item))
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|it| it.0);
+        let canonical = serde_json::to_vec(&(&self.title, slots))?;
+        let digest = Sha256::digest(canonical);
+        Ok(format!("{digest:x}"))
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Report {
     inventory: Inventory,
     reporter_uuid: uuid::Uuid,
     report_timestamp: MillisecondTimestamp,
     report_uuid: uuid::Uuid,
+    /// How many players submitted this exact inventory. Tracked outside of the stored report.
+    #[serde(default)]
+    reporter_count: u64,
+    /// Tracked outside of the stored report.
+    #[serde(default)]
+    status: ReportStatus,
+    /// Tracked outside of the stored report.
+    #[serde(default)]
+    notes: Vec<Note>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    #[default]
+    Open,
+    Triaged,
+    Resolved,
+    Spam,
+}
+
+impl ReportStatus {
+    /// Closed reports are done with, so reporting the same inventory again starts a new report.
+    fn is_closed(self) -> bool {
+        matches!(self, ReportStatus::Resolved | ReportStatus::Spam)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Note {
+    author: Uuid,
+    author_name: String,
+    timestamp: MillisecondTimestamp,
+    text: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ModerationAction {
+    SetStatus { status: ReportStatus },
+    AddNote { text: String },
+    Delete,
+}
+
+/// An entry in the moderation changelog.
+#[derive(Deserialize, Serialize)]
+pub struct ModerationEvent {
+    actor: Uuid,
+    actor_name: String,
+    timestamp: MillisecondTimestamp,
+    report_uuid: Uuid,
+    #[serde(flatten)]
+    action: ModerationAction,
 }
 
 async fn report_inventory(
-    context: RequestContext,
+    mut context: NeuContext,
+    principal: &JWTPrincipal,
+) -> anyhow::Result<Response<Body>> {
+    let usage = context
+        .storage
+        .increment_window(
+            &report_rate_limit_key(principal.id),
+            REPORT_RATE_LIMIT_WINDOW,
+        )
+        .await?;
+    if usage > REPORT_RATE_LIMIT {
+        return make_error(429, "Too many inventory reports");
+    }
+    let Some(buffer) = read_body_limited(context.request.into_body(), MAX_REPORT_SIZE).await?
+    else {
+        return make_error(413, "Report too large");
+    };
+    let mut payload = match serde_json::from_slice::<Inventory>(&buffer) {
+        Ok(payload) => payload,
+        Err(err) => return make_error(400, &format!("Malformed report: {err}")),
+    };
+    if let Err(err) = payload.validate() {
+        return make_error(400, &err);
+    }
+    let existing: Option<String> = redis::Cmd::get(content_hash_key(&payload.content_hash()?))
+        .query_async(&mut context.redis_client.0)
+        .await?;
+    let deduplicated = match existing {
+        Some(report_id) => {
+            add_reporter(&mut context.redis_client, &report_id, principal.id).await?
+        }
+        None => false,
+    };
+    if !deduplicated {
+        let report = Report {
+            inventory: payload,
+            reporter_uuid: principal.id,
+            report_timestamp: MillisecondTimestamp::now()?,
+            report_uuid: uuid::Uuid::new_v4(),
+            reporter_count: 1,
+            status: ReportStatus::Open,
+            notes: vec![],
+        };
+        store_report(&mut context.redis_client, &report).await?;
+    }
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body("{\"message\": \"§aThank you for helping us help you help us all!\"}".into())?)
+}
+
+/// Largest accepted body for moderation requests in bytes.
+const MAX_MODERATION_SIZE: usize = 16 * 1024;
+const DEFAULT_CHANGELOG_SIZE: isize = 100;
+/// How many entries are kept in each changelog.
+const MAX_CHANGELOG_SIZE: isize = 10000;
+
+#[derive(Deserialize)]
+struct StatusRequest {
+    status: ReportStatus,
+}
+
+#[derive(Deserialize)]
+struct NoteRequest {
+    text: String,
+}
+
+async fn read_moderation_request<T: for<'a> Deserialize<'a>>(
+    body: Body,
+) -> anyhow::Result<Result<T, Response<Body>>> {
+    let Some(buffer) = read_body_limited(body, MAX_MODERATION_SIZE).await? else {
+        return Ok(Err(make_error(413, "Request too large")?));
+    };
+    match serde_json::from_slice::<T>(&buffer) {
+        Ok(request) => Ok(Ok(request)),
+        Err(err) => Ok(Err(make_error(400, &format!("Malformed request: {err}"))?)),
+    }
+}
+
+async fn report_exists(redis: &mut ConnectionManager, report_uuid: Uuid) -> anyhow::Result<bool> {
+    Ok(redis::Cmd::exists(report_key(report_uuid))
+        .query_async(redis)
+        .await?)
+}
+
+async fn log_moderation(
+    redis: &mut ConnectionManager,
+    principal: &JWTPrincipal,
+    report_uuid: Uuid,
+    action: ModerationAction,
+) -> anyhow::Result<()> {
+    let event = serde_json::to_vec(&ModerationEvent {
+        actor: principal.id,
+        actor_name: principal.name.clone(),
+        timestamp: MillisecondTimestamp::now()?,
+        report_uuid,
+        action,
+    })?;
+    let actor_key = actor_changelog_key(principal.id);
+    redis::pipe()
+        .lpush(MODERATION_CHANGELOG, &event)
+        .ignore()
+        .ltrim(MODERATION_CHANGELOG, 0, MAX_CHANGELOG_SIZE - 1)
+        .ignore()
+        .lpush(&actor_key, &event)
+        .ignore()
+        .ltrim(&actor_key, 0, MAX_CHANGELOG_SIZE - 1)
+        .ignore()
+        .query_async::<_, ()>(redis)
+        .await?;
+    Ok(())
+}
+
+fn moderation_response(message: &str) -> anyhow::Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&serde_json::json!({ "message": message }))?.into())?)
+}
+
+async fn set_status(
+    context: NeuContext,
+    report_uuid: Uuid,
     principal: &JWTPrincipal,
 ) -> anyhow::Result<Response<Body>> {
-    let buffer = hyper::body::aggregate(context.request).await?;
-    let payload = serde_json::from_reader::<_, Inventory>(buffer.reader())?;
-    let report = Report {
-        inventory: payload,
-        reporter_uuid: principal.id,
-        report_timestamp: MillisecondTimestamp::now()?,
-        report_uuid: uuid::Uuid::new_v4(),
+    let NeuContext {
+        mut redis_client,
+        request,
+        ..
+    } = context;
+    let request = match read_moderation_request::<StatusRequest>(request.into_body()).await? {
+        Ok(it) => it,
+        Err(response) => return Ok(response),
     };
-    let stringified = serde_json::to_vec(&report)?;
-    tokio::fs::create_dir_all("reports").await?;
-    let mut file = tokio::fs::File::create(format!("reports/{}.json", report.report_uuid)).await?;
-    file.write_all(&stringified).await?;
+    if !report_exists(&mut redis_client, report_uuid).await? {
+        return make_error(404, "Unknown report");
+    }
+    redis::Cmd::set(
+        status_key(report_uuid),
+        serde_json::to_string(&request.status)?,
+    )
+    .query_async::<_, ()>(&mut redis_client.0)
+    .await?;
+    log_moderation(
+        &mut redis_client,
+        principal,
+        report_uuid,
+        ModerationAction::SetStatus {
+            status: request.status,
+        },
+    )
+    .await?;
+    moderation_response("Status updated")
+}
+
+async fn add_note(
+    context: NeuContext,
+    report_uuid: Uuid,
+    principal: &JWTPrincipal,
+) -> anyhow::Result<Response<Body>> {
+    let NeuContext {
+        mut redis_client,
+        request,
+        ..
+    } = context;
+    let request = match read_moderation_request::<NoteRequest>(request.into_body()).await? {
+        Ok(it) => it,
+        Err(response) => return Ok(response),
+    };
+    if !report_exists(&mut redis_client, report_uuid).await? {
+        return make_error(404, "Unknown report");
+    }
+    let note = Note {
+        author: principal.id,
+        author_name: principal.name.clone(),
+        timestamp: MillisecondTimestamp::now()?,
+        text: request.text.clone(),
+    };
+    redis::Cmd::rpush(notes_key(report_uuid), serde_json::to_vec(&note)?)
+        .query_async::<_, ()>(&mut redis_client.0)
+        .await?;
+    log_moderation(
+        &mut redis_client,
+        principal,
+        report_uuid,
+        ModerationAction::AddNote { text: request.text },
+    )
+    .await?;
+    moderation_response("Note added")
+}
+
+async fn delete_inventory(
+    mut context: NeuContext,
+    report_uuid: Uuid,
+    principal: &JWTPrincipal,
+) -> anyhow::Result<Response<Body>> {
+    let report_id = report_uuid.to_string();
+    let Some(report) = load_reports(&mut context.redis_client, &[report_id.clone()])
+        .await?
+        .pop()
+    else {
+        return make_error(404, "Unknown report");
+    };
+    let reporters: Vec<String> = redis::Cmd::smembers(reporters_key(&report_id))
+        .query_async(&mut context.redis_client.0)
+        .await?;
+    let hash_key = content_hash_key(&report.inventory.content_hash()?);
+    let hashed_report: Option<String> = redis::Cmd::get(&hash_key)
+        .query_async(&mut context.redis_client.0)
+        .await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .del(&[
+            report_key(&report_id),
+            reporters_key(&report_id),
+            status_key(&report_id),
+            notes_key(&report_id),
+        ])
+        .ignore()
+        .zrem(REPORTS_BY_TIME, &report_id)
+        .ignore()
+        .zrem(title_index_key(&report.inventory.title), &report_id)
+        .ignore()
+        .zrem(reporter_index_key(report.reporter_uuid), &report_id)
+        .ignore();
+    for reporter in reporters {
+        pipe.zrem(reporter_index_key(reporter), &report_id).ignore();
+    }
+    for skyblock_id in report.inventory.skyblock_ids() {
+        pipe.zrem(item_index_key(skyblock_id), &report_id).ignore();
+    }
+    if hashed_report.as_deref() == Some(report_id.as_str()) {
+        pipe.del(&hash_key).ignore();
+    }
+    pipe.query_async::<_, ()>(&mut context.redis_client.0)
+        .await?;
+    log_moderation(
+        &mut context.redis_client,
+        principal,
+        report_uuid,
+        ModerationAction::Delete,
+    )
+    .await?;
+    moderation_response("Report deleted")
+}
+
+#[derive(Serialize)]
+struct Changelog {
+    entries: Vec<ModerationEvent>,
+}
+
+async fn request_changelog(mut context: NeuContext) -> anyhow::Result<Response<Body>> {
+    let mut key = MODERATION_CHANGELOG.to_owned();
+    let mut limit = DEFAULT_CHANGELOG_SIZE;
+    let query = context.request.uri().query().unwrap_or("").to_owned();
+    for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match &*name {
+            "actor" => match Uuid::parse_str(&value) {
+                Ok(actor) => key = actor_changelog_key(actor),
+                Err(_) => return make_error(400, "Invalid actor uuid"),
+            },
+            "limit" => match value.parse::<isize>() {
+                Ok(it) => limit = it.clamp(1, MAX_CHANGELOG_SIZE),
+                Err(_) => return make_error(400, "Invalid limit"),
+            },
+            _ => return make_error(400, &format!("Unknown query parameter {name}")),
+        }
+    }
+    let raw: Vec<Vec<u8>> = redis::Cmd::lrange(key, 0, limit - 1)
+        .query_async(&mut context.redis_client.0)
+        .await?;
+    let entries = raw
+        .iter()
+        .map(|it| serde_json::from_slice(it))
+        .collect::<Result<_, _>>()?;
     Ok(Response::builder()
         .status(200)
         .header("content-type", "application/json")
-        .body("{\"message\": \"§aThank you for helping us help you help us all!\"}".into())?
-        .into())
+        .body(serde_json::to_string(&Changelog { entries })?.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(title: &str, skyblock_ids: &[&str], status: &str) -> Report {
+        let slots = skyblock_ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| {
+                serde_json::json!({
+                    "slot_index": index,
+                    "item": null,
+                    "decoded": {"skyblock_id": id, "display_name": null, "uuid": null},
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "inventory": {"title": title, "slots": slots},
+            "reporter_uuid": "069a79f4-44e9-4726-a5be-fca90e38aaf5",
+            "report_timestamp": 1700000000000u64,
+            "report_uuid": "61699b2e-d327-4a01-9f1e-0ea8c3f06bc6",
+            "status": status,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let text = "1700000000000:61699b2e-d327-4a01-9f1e-0ea8c3f06bc6";
+        let cursor = Cursor::parse(text).unwrap();
+        assert_eq!(cursor.timestamp, 1700000000000);
+        assert_eq!(cursor.to_string(), text);
+        let report = report("Chest", &[], "open");
+        assert_eq!(Cursor::of(&report).to_string(), text);
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        for text in [
+            "",
+            "1700000000000",
+            "abc:61699b2e-d327-4a01-9f1e-0ea8c3f06bc6",
+            "1700000000000:abc",
+            "-1:61699b2e-d327-4a01-9f1e-0ea8c3f06bc6",
+        ] {
+            assert!(Cursor::parse(text).is_none(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn cursor_orders_by_timestamp_then_id() {
+        let cursor = Cursor::parse("100:61699b2e-d327-4a01-9f1e-0ea8c3f06bc6").unwrap();
+        assert!(cursor.is_before(99, "ffffffff-ffff-ffff-ffff-ffffffffffff"));
+        assert!(cursor.is_before(100, "00000000-0000-0000-0000-000000000000"));
+        assert!(!cursor.is_before(100, "61699b2e-d327-4a01-9f1e-0ea8c3f06bc6"));
+        assert!(!cursor.is_before(100, "ffffffff-ffff-ffff-ffff-ffffffffffff"));
+        assert!(!cursor.is_before(101, "00000000-0000-0000-0000-000000000000"));
+    }
+
+    #[test]
+    fn query_filters_reports() {
+        let report = report(
+            "Auction House",
+            &["HYPERION", "ASPECT_OF_THE_END"],
+            "triaged",
+        );
+        let matches = |query: &str| ReportQuery::parse(query).unwrap().matches(&report);
+        assert!(matches(""));
+        assert!(matches("title=auction"));
+        assert!(matches("title=AUCTION+house"));
+        assert!(!matches("title=bazaar"));
+        assert!(matches("exact_title=Auction+House"));
+        assert!(!matches("exact_title=auction+house"));
+        assert!(matches("item=HYPERION"));
+        assert!(!matches("item=TERMINATOR"));
+        assert!(matches("status=triaged"));
+        assert!(!matches("status=open"));
+        assert!(matches("title=house&item=ASPECT_OF_THE_END&status=triaged"));
+        assert!(!matches("title=house&item=TERMINATOR"));
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        for query in [
+            "cursor=abc",
+            "limit=many",
+            "reporter=abc",
+            "status=closed",
+            "from=yesterday",
+            "unknown=1",
+        ] {
+            assert!(ReportQuery::parse(query).is_err(), "{query:?}");
+        }
+        assert_eq!(ReportQuery::parse("limit=0").unwrap().limit, 1);
+        assert_eq!(
+            ReportQuery::parse("limit=100000").unwrap().limit,
+            MAX_PAGE_SIZE
+        );
+    }
 }
@@ -0,0 +1,533 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+
+use hyper::{Body, Response};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::storage::{ScoreIncrement, Storage};
+use crate::util::MillisecondTimestamp;
+use crate::{global_application_config, make_error, RequestContext};
+
+/// All time user agent counts, kept for existing consumers of the statistics.
+const USER_AGENT_KEY: &str = "user-agent";
+/// Longer user agents are cut off, to keep arbitrary headers from bloating the statistics.
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// Client statistics are not interesting at a finer granularity.
+const CLIENT_GRANULARITIES: [Granularity; 2] = [Granularity::Hour, Granularity::Day];
+const DEFAULT_CLIENT_LIMIT: usize = 20;
+const MAX_CLIENT_LIMIT: usize = 100;
+const DEFAULT_BUCKET_COUNT: usize = 7;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Granularity {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "minute" => Some(Granularity::Minute),
+            "hour" => Some(Granularity::Hour),
+            "day" => Some(Granularity::Day),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    pub fn duration(self) -> Duration {
+        match self {
+            Granularity::Minute => Duration::from_secs(60),
+            Granularity::Hour => Duration::from_secs(60 * 60),
+            Granularity::Day => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    /// How long buckets of this granularity are kept.
+    pub fn retention(self) -> Duration {
+        match self {
+            Granularity::Minute => Duration::from_secs(24 * 60 * 60),
+            Granularity::Hour => Duration::from_secs(7 * 24 * 60 * 60),
+            Granularity::Day => Duration::from_secs(90 * 24 * 60 * 60),
+        }
+    }
+
+    fn max_buckets(self) -> usize {
+        (self.retention().as_secs() / self.duration().as_secs()) as usize
+    }
+
+    /// The start of the bucket containing `timestamp`. Buckets are aligned to UTC.
+    pub fn bucket_start(self, timestamp: MillisecondTimestamp) -> MillisecondTimestamp {
+        let length = self.duration().as_millis() as u64;
+        MillisecondTimestamp(timestamp.0 - timestamp.0 % length)
+    }
+}
+
+fn client_key(granularity: Granularity, bucket: MillisecondTimestamp) -> String {
+    format!("user-agent:{}:{}", granularity.name(), bucket.0)
+}
+
+/// Count a request made by `user_agent`, both all time and in the current bucket of every
+/// granularity.
+pub async fn record_client(storage: &dyn Storage, user_agent: &str) -> anyhow::Result<()> {
+    let user_agent = match user_agent.char_indices().nth(MAX_USER_AGENT_LENGTH) {
+        Some((end, _)) => &user_agent[..end],
+        None => user_agent,
+    };
+    let now = MillisecondTimestamp::from(SystemTime::now());
+    let mut increments = vec![ScoreIncrement {
+        key: USER_AGENT_KEY.to_owned(),
+        member: user_agent.to_owned(),
+        by: 1,
+        lifespan: None,
+    }];
+    for granularity in CLIENT_GRANULARITIES {
+        increments.push(ScoreIncrement {
+            key: client_key(granularity, granularity.bucket_start(now)),
+            member: user_agent.to_owned(),
+            by: 1,
+            lifespan: Some(granularity.retention()),
+        });
+    }
+    storage.increment_scores(increments).await
+}
+
+/// Split the leading product of a user agent, like `NotEnoughUpdates/2.4.0 (Minecraft 1.8.9)`,
+/// into the mod name and version.
+fn parse_user_agent(user_agent: &str) -> (&str, Option<&str>) {
+    let product = user_agent.split_whitespace().next().unwrap_or("");
+    match product.split_once('/') {
+        Some((name, version)) if !version.is_empty() => (name, Some(version)),
+        Some((name, _)) => (name, None),
+        None => (product, None),
+    }
+}
+
+#[derive(Serialize)]
+struct ClientCount {
+    user_agent: String,
+    name: String,
+    version: Option<String>,
+    requests: u64,
+}
+
+#[derive(Serialize)]
+struct ClientBucket {
+    start: MillisecondTimestamp,
+    clients: Vec<ClientCount>,
+}
+
+#[derive(Serialize)]
+struct Clients {
+    granularity: Granularity,
+    buckets: Vec<ClientBucket>,
+}
+
+struct ClientQuery {
+    granularity: Granularity,
+    buckets: usize,
+    limit: usize,
+}
+
+impl ClientQuery {
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut result = ClientQuery {
+            granularity: Granularity::Day,
+            buckets: DEFAULT_BUCKET_COUNT,
+            limit: DEFAULT_CLIENT_LIMIT,
+        };
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "granularity" => {
+                    result.granularity = Granularity::parse(&value)
+                        .filter(|it| CLIENT_GRANULARITIES.contains(it))
+                        .ok_or_else(|| "Invalid granularity, expected hour or day".to_owned())?
+                }
+                "buckets" => {
+                    result.buckets = value
+                        .parse::<usize>()
+                        .map_err(|_| "Invalid bucket count".to_owned())?
+                        .max(1)
+                }
+                "limit" => {
+                    result.limit = value
+                        .parse::<usize>()
+                        .map_err(|_| "Invalid limit".to_owned())?
+                        .clamp(1, MAX_CLIENT_LIMIT)
+                }
+                _ => return Err(format!("Unknown query parameter {key}")),
+            }
+        }
+        result.buckets = result.buckets.min(result.granularity.max_buckets());
+        Ok(result)
+    }
+}
+
+/// The most common clients in each of the most recent buckets, newest first.
+pub async fn respond_to_clients(req: RequestContext) -> anyhow::Result<Response<Body>> {
+    let query = match ClientQuery::parse(req.request.uri().query().unwrap_or("")) {
+        Ok(query) => query,
+        Err(message) => return make_error(400, &message),
+    };
+    let granularity = query.granularity;
+    let newest = granularity.bucket_start(MillisecondTimestamp::from(SystemTime::now()));
+    let length = granularity.duration().as_millis() as u64;
+    let starts = (0..query.buckets as u64)
+        .map(|index| MillisecondTimestamp(newest.0.saturating_sub(index * length)))
+        .collect::<Vec<_>>();
+    let scores = match futures::future::try_join_all(starts.iter().map(|start| {
+        req.storage
+            .top_scores(&client_key(granularity, *start), query.limit)
+    }))
+    .await
+    {
+        Ok(it) => it,
+        Err(err) => {
+            warn!(%err, "Could not load client statistics");
+            return make_error(503, "Statistics are currently unavailable");
+        }
+    };
+    let buckets = starts
+        .into_iter()
+        .zip(scores)
+        .map(|(start, scores)| ClientBucket {
+            start,
+            clients: scores
+                .into_iter()
+                .map(|(user_agent, requests)| {
+                    let (name, version) = parse_user_agent(&user_agent);
+                    ClientCount {
+                        name: name.to_owned(),
+                        version: version.map(str::to_owned),
+                        user_agent,
+                        requests,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(
+            serde_json::to_string(&Clients {
+                granularity,
+                buckets,
+            })?
+            .into(),
+        )?)
+}
+
+/// Request counts per rule are kept per minute and per hour.
+const REQUEST_GRANULARITIES: [Granularity; 2] = [Granularity::Minute, Granularity::Hour];
+/// The most requested arguments are only kept per hour, as there are far more of them.
+const ARGUMENT_GRANULARITY: Granularity = Granularity::Hour;
+/// How many of the most requested arguments are kept per rule and window.
+const ARGUMENT_CAPACITY: usize = 500;
+const DEFAULT_ARGUMENT_LIMIT: usize = 20;
+/// Upper bound for how many buckets a single statistics query may span, beyond the first one. A
+/// day of minutes fits exactly.
+const MAX_STATS_BUCKETS: u64 = 1440;
+const DEFAULT_STATS_RANGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn request_key(granularity: Granularity, bucket: MillisecondTimestamp) -> String {
+    format!("hypixel:requests:{}:{}", granularity.name(), bucket.0)
+}
+
+/// All-time request count of a rule, kept alongside the buckets.
+fn accumulated_key(http_path: &str) -> String {
+    format!("hypixel:accumulated:{http_path}")
+}
+
+fn argument_key(http_path: &str, bucket: MillisecondTimestamp) -> String {
+    format!(
+        "hypixel:request:{http_path}:{}:{}",
+        ARGUMENT_GRANULARITY.name(),
+        bucket.0
+    )
+}
+
+/// Count a request to the rule at `http_path` in the current bucket of every granularity and in
+/// its all-time total.
+pub async fn record_request(storage: &dyn Storage, http_path: &str) -> anyhow::Result<()> {
+    let now = MillisecondTimestamp::from(SystemTime::now());
+    let buckets = futures::future::try_join_all(REQUEST_GRANULARITIES.map(|granularity| {
+        storage.increment_score_expiring(
+            &request_key(granularity, granularity.bucket_start(now)),
+            http_path,
+            1,
+            granularity.retention(),
+        )
+    }));
+    futures::try_join!(buckets, storage.increment(&accumulated_key(http_path), 1))?;
+    Ok(())
+}
+
+/// Count the arguments of a request to the rule at `http_path`, keeping only the most requested
+/// arguments of each window.
+pub async fn record_arguments(
+    storage: &dyn Storage,
+    http_path: &str,
+    arguments: &str,
+) -> anyhow::Result<()> {
+    let now = MillisecondTimestamp::from(SystemTime::now());
+    storage
+        .increment_score_capped(
+            &argument_key(http_path, ARGUMENT_GRANULARITY.bucket_start(now)),
+            arguments,
+            1,
+            ARGUMENT_GRANULARITY.retention(),
+            ARGUMENT_CAPACITY,
+        )
+        .await
+}
+
+/// The starts of all buckets overlapping `from..=to`, oldest first.
+fn bucket_starts(
+    granularity: Granularity,
+    from: MillisecondTimestamp,
+    to: MillisecondTimestamp,
+) -> impl Iterator<Item = MillisecondTimestamp> {
+    let length = granularity.duration().as_millis() as u64;
+    let first = granularity.bucket_start(from).0;
+    let last = granularity.bucket_start(to).0;
+    (first..=last)
+        .step_by(length as usize)
+        .map(MillisecondTimestamp)
+}
+
+#[derive(Serialize)]
+struct RequestBucket {
+    start: MillisecondTimestamp,
+    requests: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct ArgumentCount {
+    arguments: String,
+    requests: u64,
+}
+
+#[derive(Serialize)]
+struct RequestStatistics {
+    granularity: Granularity,
+    from: MillisecondTimestamp,
+    to: MillisecondTimestamp,
+    buckets: Vec<RequestBucket>,
+    /// Requests per rule since statistics were first recorded.
+    request_total: BTreeMap<String, u64>,
+    /// Requests per rule summed over the buckets above.
+    request_window_total: BTreeMap<String, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_arguments: Option<Vec<ArgumentCount>>,
+}
+
+struct StatsQuery {
+    granularity: Granularity,
+    from: MillisecondTimestamp,
+    to: MillisecondTimestamp,
+    rule: Option<String>,
+    limit: usize,
+}
+
+impl StatsQuery {
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut granularity = Granularity::Hour;
+        let mut from = None;
+        let mut to = None;
+        let mut rule = None;
+        let mut limit = DEFAULT_ARGUMENT_LIMIT;
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "granularity" => {
+                    granularity = Granularity::parse(&value)
+                        .filter(|it| REQUEST_GRANULARITIES.contains(it))
+                        .ok_or_else(|| "Invalid granularity, expected minute or hour".to_owned())?
+                }
+                "from" => from = Some(value.parse().map_err(|_| "Invalid from".to_owned())?),
+                "to" => to = Some(value.parse().map_err(|_| "Invalid to".to_owned())?),
+                "rule" => {
+                    if !global_application_config
+                        .rules
+                        .iter()
+                        .any(|it| it.http_path == value)
+                    {
+                        return Err(format!("Unknown rule {value}"));
+                    }
+                    rule = Some(value.into_owned())
+                }
+                "limit" => {
+                    limit = value
+                        .parse::<usize>()
+                        .map_err(|_| "Invalid limit".to_owned())?
+                        .clamp(1, ARGUMENT_CAPACITY)
+                }
+                _ => return Err(format!("Unknown query parameter {key}")),
+            }
+        }
+        let to = MillisecondTimestamp(
+            to.unwrap_or_else(|| MillisecondTimestamp::from(SystemTime::now()).0),
+        );
+        let from = MillisecondTimestamp(
+            from.unwrap_or_else(|| to.0.saturating_sub(DEFAULT_STATS_RANGE.as_millis() as u64)),
+        );
+        if from > to {
+            return Err("from must not be after to".to_owned());
+        }
+        let span = (granularity.bucket_start(to).0 - granularity.bucket_start(from).0)
+            / granularity.duration().as_millis() as u64;
+        if span > MAX_STATS_BUCKETS {
+            return Err(format!(
+                "Queries may span at most {MAX_STATS_BUCKETS} buckets, use a coarser granularity"
+            ));
+        }
+        Ok(StatsQuery {
+            granularity,
+            from,
+            to,
+            rule,
+            limit,
+        })
+    }
+}
+
+/// Request counts per rule in each bucket between `from` and `to`, and the most requested
+/// arguments of a single rule if one is selected.
+pub async fn respond_to_statistics(req: RequestContext) -> anyhow::Result<Response<Body>> {
+    let query = match StatsQuery::parse(req.request.uri().query().unwrap_or("")) {
+        Ok(query) => query,
+        Err(message) => return make_error(400, &message),
+    };
+    match load_statistics(req.storage.as_ref(), query).await {
+        Ok(statistics) => Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&statistics)?.into())?),
+        Err(err) => {
+            warn!(%err, "Could not load request statistics");
+            make_error(503, "Statistics are currently unavailable")
+        }
+    }
+}
+
+async fn load_statistics(
+    storage: &dyn Storage,
+    query: StatsQuery,
+) -> anyhow::Result<RequestStatistics> {
+    let starts = bucket_starts(query.granularity, query.from, query.to).collect::<Vec<_>>();
+    let scores = futures::future::try_join_all(starts.iter().map(|start| {
+        storage.top_scores(
+            &request_key(query.granularity, *start),
+            global_application_config.rules.len(),
+        )
+    }))
+    .await?;
+    let http_paths = global_application_config
+        .rules
+        .iter()
+        .map(|it| &it.http_path)
+        .filter(|http_path| query.rule.as_ref().map_or(true, |it| it == *http_path))
+        .collect::<Vec<_>>();
+    let totals = storage
+        .counters(
+            &http_paths
+                .iter()
+                .map(|it| accumulated_key(it))
+                .collect::<Vec<_>>(),
+        )
+        .await?;
+    let request_total = http_paths
+        .into_iter()
+        .cloned()
+        .zip(totals)
+        .collect::<BTreeMap<_, _>>();
+    let mut request_window_total = BTreeMap::new();
+    let mut buckets = Vec::with_capacity(starts.len());
+    for (start, scores) in starts.into_iter().zip(scores) {
+        let requests = scores
+            .into_iter()
+            .filter(|(http_path, _)| query.rule.as_ref().map_or(true, |it| it == http_path))
+            .collect::<BTreeMap<_, _>>();
+        for (http_path, count) in &requests {
+            *request_window_total.entry(http_path.clone()).or_default() += count;
+        }
+        buckets.push(RequestBucket { start, requests });
+    }
+    let top_arguments = match &query.rule {
+        None => None,
+        Some(http_path) => Some(load_top_arguments(storage, http_path, &query).await?),
+    };
+    Ok(RequestStatistics {
+        granularity: query.granularity,
+        from: query.from,
+        to: query.to,
+        buckets,
+        request_total,
+        request_window_total,
+        top_arguments,
+    })
+}
+
+/// Sum up the most requested arguments of every window overlapping the query. Windows only store
+/// their own most requested arguments, so the result is an approximation.
+async fn load_top_arguments(
+    storage: &dyn Storage,
+    http_path: &str,
+    query: &StatsQuery,
+) -> anyhow::Result<Vec<ArgumentCount>> {
+    let retained_since = MillisecondTimestamp::from(SystemTime::now())
+        .0
+        .saturating_sub(ARGUMENT_GRANULARITY.retention().as_millis() as u64);
+    let from = MillisecondTimestamp(query.from.0.max(retained_since));
+    if from > query.to {
+        return Ok(vec![]);
+    }
+    let scores = futures::future::try_join_all(
+        bucket_starts(ARGUMENT_GRANULARITY, from, query.to)
+            .map(|start| storage.top_scores(&argument_key(http_path, start), ARGUMENT_CAPACITY)),
+    )
+    .await?;
+    let mut totals = HashMap::<String, u64>::new();
+    for (arguments, count) in scores.into_iter().flatten() {
+        *totals.entry(arguments).or_default() += count;
+    }
+    let mut top = totals
+        .into_iter()
+        .map(|(arguments, requests)| ArgumentCount {
+            arguments,
+            requests,
+        })
+        .collect::<Vec<_>>();
+    top.sort_unstable_by(|a, b| {
+        b.requests
+            .cmp(&a.requests)
+            .then_with(|| a.arguments.cmp(&b.arguments))
+    });
+    top.truncate(query.limit);
+    Ok(top)
+}
@@ -0,0 +1,817 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use redis::aio::ConnectionManager;
+use tracing::{info, warn};
+
+use crate::util::Obscure;
+
+/// One score increment of a batch passed to [Storage::increment_scores].
+pub struct ScoreIncrement {
+    pub key: String,
+    pub member: String,
+    pub by: u64,
+    /// Remove the sorted set once this has passed since it was created.
+    pub lifespan: Option<Duration>,
+}
+
+/// Where statistics, rate limits and cached lookups are kept. NEU inventory reports are not part
+/// of this, as they need to be persistent, and are only available with redis.
+pub trait Storage: Send + Sync + Debug {
+    /// Check whether the storage is reachable.
+    fn ping(&self) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// Increment a counter, returning its new value.
+    fn increment(&self, key: &str, by: u64) -> BoxFuture<'static, anyhow::Result<u64>>;
+
+    /// Increment a counter that gets removed once `window` has passed since it was created,
+    /// returning its new value.
+    fn increment_window(
+        &self,
+        key: &str,
+        window: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<u64>>;
+
+    /// Read multiple counters at once. Missing counters are reported as 0.
+    fn counters(&self, keys: &[String]) -> BoxFuture<'static, anyhow::Result<Vec<u64>>>;
+
+    /// Increment the score of `member` in the sorted set at `key`.
+    fn increment_score(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// Increment the score of `member` in the sorted set at `key`, which gets removed once
+    /// `lifespan` has passed since it was created.
+    fn increment_score_expiring(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+        lifespan: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// Apply several score increments at once, in a single round trip.
+    fn increment_scores(
+        &self,
+        increments: Vec<ScoreIncrement>,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// Like [`Storage::increment_score_expiring`], but afterwards only the `capacity` highest
+    /// scoring members are kept. Members that are removed lose their score, so scores of rarely
+    /// seen members are only approximate.
+    fn increment_score_capped(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+        lifespan: Duration,
+        capacity: usize,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// The `limit` highest scoring members of the sorted set at `key`, highest first.
+    fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<(String, u64)>>>;
+
+    fn get(&self, key: &str) -> BoxFuture<'static, anyhow::Result<Option<String>>>;
+
+    fn set_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        lifespan: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// The underlying redis connection, for data that only redis can store. Fails while redis is
+    /// not connected yet.
+    fn redis(&self) -> Option<anyhow::Result<ConnectionManager>> {
+        None
+    }
+}
+
+/// Which storage to use, as selected by `URSA_STORAGE`.
+#[derive(Debug)]
+pub enum StorageBackend {
+    Redis { url: Obscure<String> },
+    Memory,
+}
+
+/// Rate limit windows counted within this process while the configured storage is unavailable.
+static LOCAL_RATE_LIMITS: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+
+/// Increment a rate limit window like [Storage::increment_window], falling back to counting
+/// within this process if the storage fails. During a redis outage every instance then enforces
+/// the limit on its own, instead of rejecting all requests.
+pub async fn increment_rate_limit(
+    storage: &dyn Storage,
+    key: &str,
+    window: Duration,
+) -> anyhow::Result<u64> {
+    match storage.increment_window(key, window).await {
+        Ok(usage) => Ok(usage),
+        Err(err) => {
+            warn!(%err, "Could not count rate limit in storage, counting locally");
+            LOCAL_RATE_LIMITS.increment_window(key, window).await
+        }
+    }
+}
+
+/// Longest wait between two attempts to connect to redis.
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+
+impl StorageBackend {
+    /// Set up the configured storage. Redis is connected to in the background, so that the server
+    /// can start while redis is unavailable. Until then, storage operations fail.
+    pub fn connect(&self) -> anyhow::Result<Arc<dyn Storage>> {
+        Ok(match self {
+            StorageBackend::Redis { url } => {
+                let client = redis::Client::open(url.as_str())?;
+                let connection = Arc::new(OnceLock::new());
+                tokio::spawn(connect_in_background(client, connection.clone()));
+                Arc::new(RedisStorage(connection))
+            }
+            StorageBackend::Memory => Arc::new(MemoryStorage::default()),
+        })
+    }
+
+    /// Connect to redis right away, for commands that cannot do anything without it. Returns
+    /// `None` for other storage backends.
+    pub async fn connect_redis(&self) -> anyhow::Result<Option<ConnectionManager>> {
+        Ok(match self {
+            StorageBackend::Redis { url } => {
+                Some(ConnectionManager::new(redis::Client::open(url.as_str())?).await?)
+            }
+            StorageBackend::Memory => None,
+        })
+    }
+}
+
+/// Keep trying to connect to redis with an increasing delay. Once connected, the
+/// [ConnectionManager] takes care of reconnecting.
+async fn connect_in_background(
+    client: redis::Client,
+    connection: Arc<OnceLock<Obscure<ConnectionManager, "ConnectionManager">>>,
+) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match ConnectionManager::new(client.clone()).await {
+            Ok(manager) => {
+                info!("Connected to redis");
+                let _ = connection.set(Obscure(manager));
+                return;
+            }
+            Err(err) => {
+                warn!(%err, "Could not connect to redis, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_CONNECT_DELAY);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RedisStorage(Arc<OnceLock<Obscure<ConnectionManager, "ConnectionManager">>>);
+
+impl RedisStorage {
+    fn connection(&self) -> anyhow::Result<ConnectionManager> {
+        match self.0.get() {
+            Some(connection) => Ok(connection.0.clone()),
+            None => bail!("Not connected to redis yet"),
+        }
+    }
+}
+
+impl Storage for RedisStorage {
+    fn ping(&self) -> BoxFuture<'static, anyhow::Result<()>> {
+        let connection = self.connection();
+        async move {
+            let mut connection = connection?;
+            redis::cmd("PING")
+                .query_async::<_, ()>(&mut connection)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn increment(&self, key: &str, by: u64) -> BoxFuture<'static, anyhow::Result<u64>> {
+        let connection = self.connection();
+        let command = redis::Cmd::incr(key, by);
+        async move { Ok(command.query_async(&mut connection?).await?) }.boxed()
+    }
+
+    fn increment_window(
+        &self,
+        key: &str,
+        window: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<u64>> {
+        let connection = self.connection();
+        let mut pipe = redis::pipe();
+        pipe.incr(key, 1)
+            .cmd("EXPIRE")
+            .arg(key)
+            .arg(window.as_secs())
+            .arg("NX");
+        async move {
+            let mut connection = connection?;
+            let (usage, ()): (u64, ()) = pipe.query_async(&mut connection).await?;
+            Ok(usage)
+        }
+        .boxed()
+    }
+
+    fn counters(&self, keys: &[String]) -> BoxFuture<'static, anyhow::Result<Vec<u64>>> {
+        let connection = self.connection();
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.get(key);
+        }
+        async move {
+            let mut connection = connection?;
+            let values: Vec<Option<u64>> = pipe.query_async(&mut connection).await?;
+            Ok(values.into_iter().map(Option::unwrap_or_default).collect())
+        }
+        .boxed()
+    }
+
+    fn increment_score(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let connection = self.connection();
+        let command = redis::Cmd::zincr(key, member, by);
+        async move {
+            let mut connection = connection?;
+            command.query_async::<_, ()>(&mut connection).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn increment_score_expiring(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+        lifespan: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let connection = self.connection();
+        let pipe = expiring_score_pipeline(key, member, by, lifespan);
+        async move {
+            let mut connection = connection?;
+            pipe.query_async::<_, ()>(&mut connection).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn increment_scores(
+        &self,
+        increments: Vec<ScoreIncrement>,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let connection = self.connection();
+        let mut pipe = redis::pipe();
+        for increment in &increments {
+            pipe.zincr(&increment.key, &increment.member, increment.by)
+                .ignore();
+            if let Some(lifespan) = increment.lifespan {
+                pipe.cmd("EXPIRE")
+                    .arg(&increment.key)
+                    .arg(lifespan.as_secs())
+                    .arg("NX")
+                    .ignore();
+            }
+        }
+        async move {
+            let mut connection = connection?;
+            pipe.query_async::<_, ()>(&mut connection).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn increment_score_capped(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+        lifespan: Duration,
+        capacity: usize,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let connection = self.connection();
+        let mut pipe = expiring_score_pipeline(key, member, by, lifespan);
+        pipe.zremrangebyrank(key, 0, -(capacity as isize) - 1)
+            .ignore();
+        async move {
+            let mut connection = connection?;
+            pipe.query_async::<_, ()>(&mut connection).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<(String, u64)>>> {
+        let connection = self.connection();
+        let end = limit.min(isize::MAX as usize) as isize - 1;
+        let command = redis::Cmd::zrevrange_withscores(key, 0, end);
+        async move {
+            let mut connection = connection?;
+            if limit == 0 {
+                return Ok(vec![]);
+            }
+            Ok(command.query_async(&mut connection).await?)
+        }
+        .boxed()
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, anyhow::Result<Option<String>>> {
+        let connection = self.connection();
+        let command = redis::Cmd::get(key);
+        async move { Ok(command.query_async(&mut connection?).await?) }.boxed()
+    }
+
+    fn set_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        lifespan: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let connection = self.connection();
+        let command = redis::Cmd::set_ex(key, value, lifespan.as_secs() as usize);
+        async move {
+            let mut connection = connection?;
+            command.query_async::<_, ()>(&mut connection).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn redis(&self) -> Option<anyhow::Result<ConnectionManager>> {
+        Some(self.connection())
+    }
+}
+
+fn expiring_score_pipeline(
+    key: &str,
+    member: &str,
+    by: u64,
+    lifespan: Duration,
+) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.zincr(key, member, by)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(key)
+        .arg(lifespan.as_secs())
+        .arg("NX")
+        .ignore();
+    pipe
+}
+
+/// How often expired entries are removed from memory. Expired entries are never visible, this
+/// only keeps entries that are not accessed again from piling up.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+enum MemoryValue {
+    Counter(u64),
+    Text(String),
+    Scores(HashMap<String, u64>),
+}
+
+struct MemoryEntry {
+    value: MemoryValue,
+    expires: Option<Instant>,
+}
+
+impl MemoryEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|it| it <= now)
+    }
+}
+
+struct MemoryState {
+    entries: HashMap<String, MemoryEntry>,
+    last_sweep: Instant,
+}
+
+/// Keeps everything within this process. Nothing survives a restart, and multiple instances do
+/// not share rate limits, so this is meant for development and single instance deployments.
+pub struct MemoryStorage(Mutex<MemoryState>);
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage(Mutex::new(MemoryState {
+            entries: HashMap::new(),
+            last_sweep: Instant::now(),
+        }))
+    }
+}
+
+impl Debug for MemoryStorage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MemoryStorage")
+    }
+}
+
+impl MemoryStorage {
+    fn with_entries<T>(
+        &self,
+        action: impl FnOnce(&mut HashMap<String, MemoryEntry>, Instant) -> anyhow::Result<T>,
+    ) -> BoxFuture<'static, anyhow::Result<T>>
+    where
+        T: Send + 'static,
+    {
+        let mut state = self.0.lock().unwrap_or_else(|it| it.into_inner());
+        let now = Instant::now();
+        if now.duration_since(state.last_sweep) >= SWEEP_INTERVAL {
+            state.entries.retain(|_, entry| !entry.is_expired(now));
+            state.last_sweep = now;
+        }
+        futures::future::ready(action(&mut state.entries, now)).boxed()
+    }
+}
+
+/// Look up a live entry, creating it if it is missing or expired.
+fn live_entry<'a>(
+    entries: &'a mut HashMap<String, MemoryEntry>,
+    key: &str,
+    now: Instant,
+    create: impl FnOnce() -> MemoryEntry,
+) -> &'a mut MemoryEntry {
+    match entries.entry(key.to_owned()) {
+        Entry::Occupied(mut it) => {
+            if it.get().is_expired(now) {
+                it.insert(create());
+            }
+            it.into_mut()
+        }
+        Entry::Vacant(it) => it.insert(create()),
+    }
+}
+
+fn add_score(
+    entries: &mut HashMap<String, MemoryEntry>,
+    key: &str,
+    member: &str,
+    by: u64,
+    now: Instant,
+    expires: Option<Instant>,
+) -> anyhow::Result<()> {
+    let entry = live_entry(entries, key, now, || MemoryEntry {
+        value: MemoryValue::Scores(HashMap::new()),
+        expires,
+    });
+    let MemoryValue::Scores(scores) = &mut entry.value else {
+        bail!("Entry is not a sorted set");
+    };
+    *scores.entry(member.to_owned()).or_default() += by;
+    Ok(())
+}
+
+fn counter(entry: &mut MemoryEntry) -> anyhow::Result<&mut u64> {
+    match &mut entry.value {
+        MemoryValue::Counter(value) => Ok(value),
+        _ => bail!("Entry is not a counter"),
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn ping(&self) -> BoxFuture<'static, anyhow::Result<()>> {
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn increment(&self, key: &str, by: u64) -> BoxFuture<'static, anyhow::Result<u64>> {
+        self.with_entries(|entries, now| {
+            let entry = live_entry(entries, key, now, || MemoryEntry {
+                value: MemoryValue::Counter(0),
+                expires: None,
+            });
+            let value = counter(entry)?;
+            *value += by;
+            Ok(*value)
+        })
+    }
+
+    fn increment_window(
+        &self,
+        key: &str,
+        window: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<u64>> {
+        self.with_entries(|entries, now| {
+            let entry = live_entry(entries, key, now, || MemoryEntry {
+                value: MemoryValue::Counter(0),
+                expires: Some(now + window),
+            });
+            let value = counter(entry)?;
+            *value += 1;
+            Ok(*value)
+        })
+    }
+
+    fn counters(&self, keys: &[String]) -> BoxFuture<'static, anyhow::Result<Vec<u64>>> {
+        self.with_entries(|entries, now| {
+            keys.iter()
+                .map(|key| match entries.get_mut(key) {
+                    Some(entry) if !entry.is_expired(now) => counter(entry).copied(),
+                    _ => Ok(0),
+                })
+                .collect()
+        })
+    }
+
+    fn increment_score(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.with_entries(|entries, now| add_score(entries, key, member, by, now, None))
+    }
+
+    fn increment_score_expiring(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+        lifespan: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.with_entries(|entries, now| {
+            add_score(entries, key, member, by, now, Some(now + lifespan))
+        })
+    }
+
+    fn increment_scores(
+        &self,
+        increments: Vec<ScoreIncrement>,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.with_entries(|entries, now| {
+            for increment in &increments {
+                add_score(
+                    entries,
+                    &increment.key,
+                    &increment.member,
+                    increment.by,
+                    now,
+                    increment.lifespan.map(|it| now + it),
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn increment_score_capped(
+        &self,
+        key: &str,
+        member: &str,
+        by: u64,
+        lifespan: Duration,
+        capacity: usize,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.with_entries(|entries, now| {
+            add_score(entries, key, member, by, now, Some(now + lifespan))?;
+            let Some(MemoryEntry {
+                value: MemoryValue::Scores(scores),
+                ..
+            }) = entries.get_mut(key)
+            else {
+                return Ok(());
+            };
+            while scores.len() > capacity {
+                // Same order as redis, removing the lowest score with the lowest member first
+                let Some(lowest) = scores
+                    .iter()
+                    .min_by(|a, b| a.1.cmp(b.1).then_with(|| a.0.cmp(b.0)))
+                    .map(|(member, _)| member.clone())
+                else {
+                    break;
+                };
+                scores.remove(&lowest);
+            }
+            Ok(())
+        })
+    }
+
+    fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<(String, u64)>>> {
+        self.with_entries(|entries, now| {
+            let scores = match entries.get(key) {
+                Some(entry) if !entry.is_expired(now) => match &entry.value {
+                    MemoryValue::Scores(scores) => scores,
+                    _ => bail!("Entry is not a sorted set"),
+                },
+                _ => return Ok(vec![]),
+            };
+            let mut top = scores
+                .iter()
+                .map(|(member, score)| (member.clone(), *score))
+                .collect::<Vec<_>>();
+            // Same order as redis: highest score first, ties in reverse lexicographical order
+            top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+            top.truncate(limit);
+            Ok(top)
+        })
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, anyhow::Result<Option<String>>> {
+        self.with_entries(|entries, now| match entries.get(key) {
+            Some(entry) if !entry.is_expired(now) => match &entry.value {
+                MemoryValue::Text(value) => Ok(Some(value.clone())),
+                _ => bail!("Entry is not a string"),
+            },
+            _ => Ok(None),
+        })
+    }
+
+    fn set_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        lifespan: Duration,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.with_entries(|entries, now| {
+            entries.insert(
+                key.to_owned(),
+                MemoryEntry {
+                    value: MemoryValue::Text(value.to_owned()),
+                    expires: Some(now + lifespan),
+                },
+            );
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Redis storage that never connected, so that every operation fails.
+    fn unavailable() -> RedisStorage {
+        RedisStorage(Arc::new(OnceLock::new()))
+    }
+
+    #[tokio::test]
+    async fn counts_and_expires_windows() {
+        let storage = MemoryStorage::default();
+        let window = Duration::from_millis(50);
+        assert_eq!(storage.increment_window("window", window).await.unwrap(), 1);
+        assert_eq!(storage.increment_window("window", window).await.unwrap(), 2);
+        assert_eq!(storage.increment("counter", 5).await.unwrap(), 5);
+        let keys = ["window", "counter", "missing"].map(str::to_owned);
+        assert_eq!(storage.counters(&keys).await.unwrap(), [2, 5, 0]);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(storage.counters(&keys).await.unwrap(), [0, 5, 0]);
+        assert_eq!(storage.increment_window("window", window).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn orders_and_caps_scores() {
+        let storage = MemoryStorage::default();
+        storage.increment_score("scores", "a", 2).await.unwrap();
+        storage.increment_score("scores", "b", 3).await.unwrap();
+        storage.increment_score("scores", "c", 2).await.unwrap();
+        storage.increment_score("scores", "a", 2).await.unwrap();
+        assert_eq!(
+            storage.top_scores("scores", 10).await.unwrap(),
+            [
+                ("a".to_owned(), 4),
+                ("b".to_owned(), 3),
+                ("c".to_owned(), 2)
+            ]
+        );
+        assert_eq!(storage.top_scores("scores", 1).await.unwrap().len(), 1);
+        assert!(storage.top_scores("missing", 10).await.unwrap().is_empty());
+
+        let lifespan = Duration::from_secs(60);
+        for member in ["x", "y", "z"] {
+            storage
+                .increment_score_capped("capped", member, 1, lifespan, 2)
+                .await
+                .unwrap();
+        }
+        // Ties drop the lowest member first, like redis
+        assert_eq!(
+            storage.top_scores("capped", 10).await.unwrap(),
+            [("z".to_owned(), 1), ("y".to_owned(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_batched_increments() {
+        let storage = MemoryStorage::default();
+        let increment = |key: &str, lifespan| ScoreIncrement {
+            key: key.to_owned(),
+            member: "client".to_owned(),
+            by: 1,
+            lifespan,
+        };
+        storage
+            .increment_scores(vec![
+                increment("forever", None),
+                increment("briefly", Some(Duration::from_millis(50))),
+                increment("forever", None),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.top_scores("forever", 10).await.unwrap(),
+            [("client".to_owned(), 2)]
+        );
+        assert_eq!(storage.top_scores("briefly", 10).await.unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(storage.top_scores("briefly", 10).await.unwrap().is_empty());
+        assert_eq!(storage.top_scores("forever", 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stores_expiring_values() {
+        let storage = MemoryStorage::default();
+        storage
+            .set_expiring("name", "value", Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(storage.get("name").await.unwrap().as_deref(), Some("value"));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(storage.get("name").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_types() {
+        let storage = MemoryStorage::default();
+        storage.increment("counter", 1).await.unwrap();
+        storage.increment_score("scores", "a", 1).await.unwrap();
+        assert!(storage.get("counter").await.is_err());
+        assert!(storage.top_scores("counter", 10).await.is_err());
+        assert!(storage.increment("scores", 1).await.is_err());
+        assert!(storage.increment_score("counter", "a", 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn counts_rate_limits_locally_while_storage_fails() {
+        let window = Duration::from_secs(60);
+        let storage = unavailable();
+        assert!(storage
+            .increment_window("test:fallback", window)
+            .await
+            .is_err());
+        for expected in 1..=3 {
+            let usage = increment_rate_limit(&storage, "test:fallback", window)
+                .await
+                .unwrap();
+            assert_eq!(usage, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_rate_limits_in_storage_while_available() {
+        let window = Duration::from_secs(60);
+        let storage = MemoryStorage::default();
+        for expected in 1..=2 {
+            let usage = increment_rate_limit(&storage, "test:available", window)
+                .await
+                .unwrap();
+            assert_eq!(usage, expected);
+        }
+        let local = LOCAL_RATE_LIMITS
+            .counters(&["test:available".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(local, [0]);
+    }
+}
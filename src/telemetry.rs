@@ -0,0 +1,136 @@
+// Ursa Minor - A Hypixel API proxy
+// Copyright (C) 2023 Linnea Gräf
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::bail;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::global_application_config;
+
+#[cfg(feature = "otlp")]
+pub const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+
+type Subscriber = tracing_subscriber::layer::Layered<EnvFilter, Registry>;
+type BoxedLayer = Box<dyn Layer<Subscriber> + Send + Sync>;
+
+/// Where traces and logs go, as configured by the comma separated `URSA_TRACING`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracingLayer {
+    /// Human readable logs on stdout
+    Plain,
+    /// JSON logs on stdout, one object per line
+    Json,
+    /// The Tracy profiler
+    #[cfg(feature = "tracy")]
+    Tracy,
+    /// Span export to an OpenTelemetry collector
+    #[cfg(feature = "otlp")]
+    Otlp,
+}
+
+impl TracingLayer {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "plain" => TracingLayer::Plain,
+            "json" => TracingLayer::Json,
+            #[cfg(feature = "tracy")]
+            "tracy" => TracingLayer::Tracy,
+            #[cfg(not(feature = "tracy"))]
+            "tracy" => {
+                bail!("Tracing layer tracy requires ursa to be built with the tracy feature")
+            }
+            #[cfg(feature = "otlp")]
+            "otlp" => TracingLayer::Otlp,
+            #[cfg(not(feature = "otlp"))]
+            "otlp" => bail!("Tracing layer otlp requires ursa to be built with the otlp feature"),
+            _ => bail!("Unknown tracing layer {name:?}, expected plain, json, tracy or otlp"),
+        })
+    }
+
+    pub fn parse_list(names: &str) -> anyhow::Result<Vec<Self>> {
+        names.split(',').map(|it| Self::parse(it.trim())).collect()
+    }
+}
+
+/// Keeps exporters alive that need to be flushed before exiting.
+#[must_use]
+pub struct TracingGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl TracingGuard {
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                tracing::warn!(%err, "Could not flush OpenTelemetry spans");
+            }
+        }
+    }
+}
+
+/// Install the configured tracing layers. `RUST_LOG` decides which events and spans get through
+/// to any of them.
+pub fn init() -> anyhow::Result<TracingGuard> {
+    #[cfg(feature = "otlp")]
+    let mut provider = None;
+    let mut layers: Vec<BoxedLayer> = vec![];
+    for layer in &global_application_config.tracing_layers {
+        layers.push(match layer {
+            TracingLayer::Plain => tracing_subscriber::fmt::layer().boxed(),
+            TracingLayer::Json => tracing_subscriber::fmt::layer().json().boxed(),
+            #[cfg(feature = "tracy")]
+            TracingLayer::Tracy => tracing_tracy::TracyLayer::default().boxed(),
+            #[cfg(feature = "otlp")]
+            TracingLayer::Otlp => {
+                let (layer, created) = otlp_layer()?;
+                provider = Some(created);
+                layer
+            }
+        });
+    }
+    let subscriber = Registry::default()
+        .with(EnvFilter::from_default_env())
+        .with(layers);
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(TracingGuard {
+        #[cfg(feature = "otlp")]
+        provider,
+    })
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer() -> anyhow::Result<(BoxedLayer, opentelemetry_sdk::trace::TracerProvider)> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(&global_application_config.otlp_endpoint)
+        .build()?;
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new([
+            opentelemetry::KeyValue::new("service.name", "ursa-minor"),
+            opentelemetry::KeyValue::new("service.version", crate::meta::BUILD_VERSION),
+        ]))
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("ursa-minor"))
+        .boxed();
+    Ok((layer, provider))
+}
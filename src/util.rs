@@ -14,16 +14,19 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+#[cfg(feature = "influxdb")]
 use chrono::Utc;
+use hyper::body::HttpBody;
 use hyper::http::request::Builder;
-use hyper::Uri;
+use hyper::{Body, Uri};
 #[cfg(feature = "influxdb")]
 use influxdb::Timestamp;
 use serde::{Deserialize, Serialize};
-use std::fmt::{Debug, Formatter, Write};
+use std::fmt::{Debug, Formatter};
 use std::ops::{Add, Deref, DerefMut, Sub};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
 // Sadly need to use Url for url encoding, since hypers uri does not have that capability
@@ -125,3 +128,24 @@ impl MillisecondTimestamp {
 pub fn pure_false() -> bool {
     false
 }
+
+/// Read a body into memory, returning [None] as soon as it exceeds `limit` bytes.
+pub async fn read_body_limited(mut body: Body, limit: usize) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buffer.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(Some(buffer))
+}
+
+/// Shutdown signals for background work. Once `requested` is cancelled no new work should be
+/// started, while work that is already running may continue until the `deadline` is cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    pub requested: CancellationToken,
+    pub deadline: CancellationToken,
+}